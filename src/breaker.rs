@@ -0,0 +1,61 @@
+use parking_lot::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Turns mounts away for a while once enough of them in a row have failed because of the host, so that a broken
+/// fuse or a full disk isn't hammered by every retry. Once the cooldown is up, mounts are let through again, and the
+/// first one to finish decides: a success closes the breaker, and a failure trips it straight away.
+#[derive(Default)]
+pub struct Breaker {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// How many mounts in a row have failed, and when the first of them did.
+    streak: Option<(u32, Instant)>,
+    /// When the breaker last tripped, and until when it turns mounts away. Still set once that's passed, until a
+    /// mount succeeds.
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    /// How long mounts are still being turned away for, if they are.
+    pub fn remaining(&self) -> Option<Duration> {
+        let state = self.state.lock();
+        let open_until = state.open_until?;
+        let now = Instant::now();
+        (open_until > now).then(|| open_until - now)
+    }
+
+    /// Counts a mount that worked, which closes the breaker.
+    pub fn succeeded(&self) {
+        let mut state = self.state.lock();
+        state.streak = None;
+        state.open_until = None;
+    }
+
+    /// Counts a mount that failed because of the host. After `threshold` of them in a row, within `window` of the
+    /// first, mounts are turned away for `cooldown`. Returns whether this tripped the breaker.
+    pub fn failed(&self, threshold: u32, window: Duration, cooldown: Duration) -> bool {
+        let mut state = self.state.lock();
+        let now = Instant::now();
+        let count = match state.streak {
+            Some((count, since)) if now.duration_since(since) <= window => {
+                state.streak = Some((count + 1, since));
+                count + 1
+            }
+            _ => {
+                state.streak = Some((1, now));
+                1
+            }
+        };
+        // The first mount after a cooldown failing means nothing's been fixed.
+        let tripped = count >= threshold || state.open_until.is_some_and(|until| until <= now);
+        if tripped {
+            state.streak = None;
+            state.open_until = Some(now + cooldown);
+        }
+        tripped
+    }
+}
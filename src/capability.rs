@@ -0,0 +1,68 @@
+use log::{info, warn};
+use std::fs;
+
+/// CAP_SYS_ADMIN's bit in the capability sets, from linux/capability.h.
+const CAP_SYS_ADMIN: u32 = 21;
+/// fuse's config file, where `user_allow_other` lets users other than root mount with `allow_other`.
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+/// Whether the fuse mounts can use `allow_other`. Without it, nobody but whoever mounted them (the web server
+/// included) can read them.
+pub struct AllowOther {
+    /// Whether `/etc/fuse.conf` has `user_allow_other`.
+    pub user_allow_other: bool,
+    /// Whether the mount binaries can use `allow_other`: they run as root, or `user_allow_other` is on.
+    pub usable: bool,
+}
+
+/// Works out whether we'll be allowed to mount anything, and warns if it looks like we won't.
+/// Root, or anything with CAP_SYS_ADMIN, can. So can a configured privilege wrapper, as far as we can
+/// tell without running it. Otherwise every mount would fail once it gets as far as the mount binaries.
+pub fn check_mount(priv_wrapper: &[String]) -> bool {
+    if !priv_wrapper.is_empty() {
+        info!(
+            "Mounting through {}, assuming it grants the privileges to mount",
+            priv_wrapper.join(" ")
+        );
+        return true;
+    }
+    // SAFETY: geteuid can't fail, and touches no memory of ours.
+    if unsafe { libc::geteuid() } == 0 || has_effective(CAP_SYS_ADMIN) {
+        return true;
+    }
+    warn!("Running without root or CAP_SYS_ADMIN, and without FPVM_PRIV_WRAPPER: mounts will fail");
+    false
+}
+
+/// Checks whether `cap` is in our effective capability set, going by `/proc/self/status`.
+fn has_effective(cap: u32) -> bool {
+    let status = match fs::read_to_string("/proc/self/status") {
+        Ok(status) => status,
+        Err(_) => return false,
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+        .is_some_and(|mask| mask & (1 << cap) != 0)
+}
+
+/// Works out whether the mount binaries will be allowed to use `allow_other`. Root always is. Through a privilege
+/// wrapper, we assume they run as root too. Anyone else needs `user_allow_other` in `/etc/fuse.conf`.
+pub fn check_allow_other(priv_wrapper: &[String]) -> AllowOther {
+    let user_allow_other = fs::read_to_string(FUSE_CONF).is_ok_and(|conf| {
+        conf.lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .any(|line| line == "user_allow_other")
+    });
+    // SAFETY: geteuid can't fail, and touches no memory of ours.
+    let root = !priv_wrapper.is_empty() || unsafe { libc::geteuid() } == 0;
+    let usable = root || user_allow_other;
+    if !usable {
+        warn!("Not running as root, and {} doesn't have user_allow_other: allow_other mounts will fail", FUSE_CONF);
+    }
+    AllowOther {
+        user_allow_other,
+        usable,
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{
+    config::Config,
+    mounts::{is_stale, read_mounts},
+    subprocess::{privileged, wait_subprocess},
+    TMP_DIR, UMOUNT, UNIONFS_MOUNTPT, UNIONFS_SHADOW_MOUNTPT,
+};
+use log::{error, info, warn};
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::fs::{read_dir, remove_dir};
+use tokio::time::timeout;
+use urlencoding::encode;
+use warp::hyper::{body, header::HeaderValue, Body, Client, Response, Uri};
+
+/// How long to give a running daemon to answer before deciding there isn't one.
+const INSTANCE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many folders deep under the tmp dir to look for leftover device dirs. Devnames with slashes in them nest.
+const SWEEP_DEPTH: usize = 4;
+
+/// The mountpoints that make up a device dir.
+const MOUNTPOINT_NAMES: [&str; 2] = ["zip", "fuzzy"];
+
+/// Unmounts everything, for `--cleanup`. If a daemon is running, it's asked to unmount each of its devices, so that
+/// it knows they're gone. It won't unmount protected devices, or the union itself. Otherwise, every fuse mount of
+/// ours in the mount table is unmounted directly. Returns whether everything that was tried worked.
+pub async fn cleanup(config: &Config, port: u16) -> bool {
+    match running_devices(port).await {
+        Some(devnames) => unmount_through_instance(port, devnames).await,
+        None => {
+            info!("No daemon is running, unmounting directly");
+            unmount_leftovers(config).await
+        }
+    }
+}
+
+/// Asks the daemon on `port` what it has mounted. `None` if there's no daemon there.
+async fn running_devices(port: u16) -> Option<Vec<String>> {
+    let response = get(port, "/list").await?;
+    let bytes = body::to_bytes(response.into_body()).await.ok()?;
+    let mut list: Value = serde_json::from_slice(&bytes).ok()?;
+    serde_json::from_value(list.get_mut("devices")?.take()).ok()
+}
+
+/// GETs `path` from the daemon on `port`. `None` if it doesn't answer.
+async fn get(port: u16, path: &str) -> Option<Response<Body>> {
+    let uri: Uri = format!("http://127.0.0.1:{}{}", port, path).parse().ok()?;
+    match timeout(INSTANCE_TIMEOUT, Client::new().get(uri)).await {
+        Ok(Ok(response)) => Some(response),
+        _ => None,
+    }
+}
+
+/// Has the running daemon unmount each of `devnames`.
+async fn unmount_through_instance(port: u16, devnames: Vec<String>) -> bool {
+    info!(
+        "Asking the running daemon to unmount {} devices",
+        devnames.len()
+    );
+    let mut all_ok = true;
+    for devname in devnames {
+        let response = match get(port, &format!("/umount?devname={}", encode(&devname))).await {
+            Some(response) => response,
+            None => {
+                error!("The daemon didn't answer the unmount of {}", devname);
+                all_ok = false;
+                continue;
+            }
+        };
+        let code = response
+            .headers()
+            .get("X-Error-Code")
+            .map(HeaderValue::as_bytes);
+        if response.status().is_success() {
+            info!("Unmounted {}", devname);
+        } else if code == Some(b"protected_device") {
+            info!("Leaving {} mounted, it's protected", devname);
+        } else {
+            error!("Could not unmount {}: {}", devname, response.status());
+            all_ok = false;
+        }
+    }
+    all_ok
+}
+
+/// Unmounts every fuse mount in our territory, newest first, so that the union goes before the mounts it's made of.
+async fn unmount_leftovers(config: &Config) -> bool {
+    let mounts = match read_mounts().await {
+        Ok(mounts) => mounts,
+        Err(err) => {
+            error!("Could not read /proc/mounts: {}", err);
+            return false;
+        }
+    };
+    let mut all_ok = true;
+    for mount in mounts.iter().rev().filter(|mount| {
+        mount.fstype.starts_with("fuse")
+            && (mount.target.starts_with(TMP_DIR)
+                || mount.target == UNIONFS_MOUNTPT
+                || mount.target == UNIONFS_SHADOW_MOUNTPT)
+    }) {
+        let mut umount = privileged(config, UMOUNT);
+        // Nothing's going to use it again, so one whose process is gone can just be detached.
+        if is_stale(&mount.target).await {
+            umount.arg("-l");
+        }
+        match wait_subprocess(umount.arg(&mount.target).spawn(), config.subprocess_timeout).await {
+            Ok(()) => info!("Unmounted {}", mount.target),
+            Err(err) => {
+                warn!("Could not unmount {}: {}", mount.target, err.code());
+                all_ok = false;
+            }
+        }
+    }
+    all_ok
+}
+
+/// Removes the device dirs that a crashed run left behind in the tmp dir, for `FPVM_SWEEP_TMP`. Only dirs that hold
+/// nothing but empty `zip` and `fuzzy` folders go, and only if none of them is mounted. Everything is removed with
+/// `rmdir`, so anything that's gained a file since we looked stays put.
+pub async fn sweep_tmp() {
+    let mounted: HashSet<String> = match read_mounts().await {
+        Ok(mounts) => mounts.into_iter().map(|mount| mount.target).collect(),
+        Err(err) => {
+            warn!(
+                "Not sweeping {}, could not read /proc/mounts: {}",
+                TMP_DIR, err
+            );
+            return;
+        }
+    };
+    let mut swept = 0;
+    let mut pending = vec![(PathBuf::from(TMP_DIR), 0)];
+    while let Some((dir, depth)) = pending.pop() {
+        let mut entries = match read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            // Symlinks aren't followed, so nothing outside the tmp dir can be reached.
+            if !entry
+                .file_type()
+                .await
+                .is_ok_and(|file_type| file_type.is_dir())
+            {
+                continue;
+            }
+            let path = entry.path();
+            if is_mounted(&mounted, &path) {
+                continue;
+            }
+            if is_leftover(&mounted, &path).await {
+                if remove_leftover(&path).await {
+                    swept += 1;
+                }
+            } else if depth + 1 < SWEEP_DEPTH {
+                pending.push((path, depth + 1));
+            }
+        }
+    }
+    if swept > 0 {
+        info!("Removed {} leftover device dirs from {}", swept, TMP_DIR);
+    }
+}
+
+/// Whether `path` is in the mount table.
+fn is_mounted(mounted: &HashSet<String>, path: &Path) -> bool {
+    path.to_str().is_some_and(|path| mounted.contains(path))
+}
+
+/// Whether `dir` looks like one of our device dirs with nothing mounted in it: it holds a `zip` or `fuzzy` folder or
+/// both, and nothing else, and they're empty and not mounted.
+async fn is_leftover(mounted: &HashSet<String>, dir: &Path) -> bool {
+    let mut entries = match read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    let mut found = false;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let is_dir = entry
+            .file_type()
+            .await
+            .is_ok_and(|file_type| file_type.is_dir());
+        let named = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| MOUNTPOINT_NAMES.contains(&name));
+        let path = entry.path();
+        if !is_dir || !named || is_mounted(mounted, &path) || !is_empty(&path).await {
+            return false;
+        }
+        found = true;
+    }
+    found
+}
+
+/// Whether `dir` has nothing in it.
+async fn is_empty(dir: &Path) -> bool {
+    match read_dir(dir).await {
+        Ok(mut entries) => matches!(entries.next_entry().await, Ok(None)),
+        Err(_) => false,
+    }
+}
+
+/// Removes a leftover device dir and its mountpoints. Returns whether it's gone.
+async fn remove_leftover(dir: &Path) -> bool {
+    for name in MOUNTPOINT_NAMES {
+        let mountpt = dir.join(name);
+        if let Err(err) = remove_dir(&mountpt).await {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("Could not remove {}: {}", mountpt.display(), err);
+                return false;
+            }
+        }
+    }
+    match remove_dir(dir).await {
+        Ok(()) => {
+            info!("Removed leftover {}", dir.display());
+            true
+        }
+        Err(err) => {
+            warn!("Could not remove {}: {}", dir.display(), err);
+            false
+        }
+    }
+}
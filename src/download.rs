@@ -0,0 +1,101 @@
+use crate::{error::MountError, sha256::Sha256};
+use log::{info, warn};
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tokio::{fs::File, io::AsyncWriteExt};
+use warp::hyper::{body::HttpBody, header::CONTENT_LENGTH, Client, Uri};
+
+/// An archive downloaded into the staging dir. The file is deleted when this is dropped, so a mount
+/// that fails (or is unmounted) doesn't leave it behind.
+pub struct StagedFile {
+    path: PathBuf,
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => warn!("Could not remove {}: {}", self.path.display(), err),
+        }
+    }
+}
+
+/// Downloads `url` to `dest`, which is overwritten if it exists. Fails if the body is longer than `max_bytes`,
+/// or if `sha256` is given and the body doesn't hash to it. Only plain `http://` URLs are supported.
+pub async fn download(
+    url: &str,
+    dest: &Path,
+    max_bytes: u64,
+    sha256: Option<&str>,
+) -> Result<StagedFile, MountError> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|_| MountError::InvalidParam("Couldn't parse url".to_owned()))?;
+    let response = Client::new()
+        .get(uri)
+        .await
+        .map_err(|err| MountError::DownloadFailed(format!("{}: {}", url, err)))?;
+    if !response.status().is_success() {
+        return Err(MountError::DownloadFailed(format!(
+            "{} answered {}",
+            url,
+            response.status()
+        )));
+    }
+    // Don't even start on something we already know is too big.
+    let announced = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if announced.is_some_and(|len| len > max_bytes) {
+        return Err(MountError::DownloadTooLarge(max_bytes));
+    }
+
+    let file = File::create(dest)
+        .await
+        .map_err(|err| MountError::StagingFailed(format!("{}: {}", dest.display(), err)))?;
+    // From here on, the file is ours to clean up.
+    let staged = StagedFile {
+        path: dest.to_owned(),
+    };
+    let mut file = file;
+    let mut body = response.into_body();
+    let mut hash = Sha256::default();
+    let mut len = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| MountError::DownloadFailed(format!("{}: {}", url, err)))?;
+        len += chunk.len() as u64;
+        if len > max_bytes {
+            return Err(MountError::DownloadTooLarge(max_bytes));
+        }
+        hash.update(&chunk);
+        file.write_all(&chunk)
+            .await
+            .map_err(|err| MountError::StagingFailed(format!("{}: {}", dest.display(), err)))?;
+    }
+    file.sync_all()
+        .await
+        .map_err(|err| MountError::StagingFailed(format!("{}: {}", dest.display(), err)))?;
+
+    let actual = hash.hex_digest();
+    if let Some(expected) = sha256 {
+        if !expected.eq_ignore_ascii_case(&actual) {
+            return Err(MountError::ChecksumMismatch {
+                expected: expected.to_owned(),
+                actual,
+            });
+        }
+    }
+    info!(
+        "Downloaded {} to {} ({} bytes, sha256 {})",
+        url,
+        dest.display(),
+        len,
+        actual
+    );
+    Ok(staged)
+}
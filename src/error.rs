@@ -0,0 +1,447 @@
+use crate::{subprocess::SubprocessError, HTTPResponse};
+use std::time::Duration;
+
+/// Why a request didn't go ahead. These are turned into responses in `to_response` and nowhere else,
+/// so the mount logic doesn't need to know anything about HTTP.
+///
+/// Failures that come down to the host (missing binaries, a full disk, a broken fuse) are 503s with an
+/// `environment_` code. A 500 means one of our own invariants broke, which is a bug in the daemon.
+#[derive(Clone, Debug)]
+pub enum MountError {
+    /// A query param couldn't be parsed. Carries a description of what was wrong with it.
+    InvalidParam(String),
+    /// The request had query params the endpoint doesn't know, and strict params are on. Carries their names.
+    UnknownParams(Vec<String>),
+    /// The devname itself is unusable: missing, empty, too long, and so on. `code` says which.
+    InvalidDevname { code: &'static str, message: String },
+    /// There's no such device.
+    DeviceNotFound(String),
+    /// The devname points at a directory rather than a device. Carries the devname, the path it resolved to,
+    /// and what kind of file is there (a directory, or a symlink to one).
+    DeviceIsDirectory {
+        devname: String,
+        path: String,
+        file_type: &'static str,
+    },
+    /// The device is there, but couldn't be read from. Carries the devname.
+    DeviceReadFailed(String),
+    /// The device couldn't be stat'd, for some reason other than it not being there. Carries the devname and why.
+    DeviceStatFailed(String, String),
+    /// The device was there when the mount started, but went away partway through.
+    DeviceDisappeared(String),
+    /// The device is mounted already. Not really a failure, so it maps to a 200.
+    AlreadyMounted,
+    /// The device isn't mounted, so there's nothing to unmount. Also maps to a 200.
+    NotMounted,
+    /// The device is protected, and can't be unmounted by clients.
+    Protected,
+    /// The device's resolved path isn't allowed by the device allow and deny lists. Carries the path.
+    DeviceNotAllowed(String),
+    /// Another request is mounting or unmounting this device right now.
+    InProgress,
+    /// The same device is mounted, or being mounted or unmounted, under another devname. Carries the device's path.
+    DeviceInUse(String),
+    /// As many devices as we're allowed are mounted already. Carries the limit.
+    CapacityReached(usize),
+    /// Too many mounts in a row have failed because of the host, so mounts are turned away for a while. Carries how
+    /// long for.
+    CircuitOpen(Duration),
+    /// Another process holds the lock on the device node.
+    LockedElsewhere,
+    /// The device node couldn't be locked.
+    DeviceLockFailed,
+    /// A mountpoint couldn't be created. Carries which one.
+    MountpointCreateFailed(&'static str),
+    /// The mountpoints couldn't be removed after unmounting.
+    MountpointRemoveFailed,
+    /// The archive couldn't be downloaded. Carries what went wrong.
+    DownloadFailed(String),
+    /// The archive is bigger than we're allowed to download. Carries the limit.
+    DownloadTooLarge(u64),
+    /// The downloaded archive didn't match the checksum the client gave.
+    ChecksumMismatch { expected: String, actual: String },
+    /// The downloaded archive couldn't be written to the staging dir. Carries what went wrong.
+    StagingFailed(String),
+    /// One of the mount binaries failed.
+    SubprocessFailed(SubprocessError),
+    /// The whole mount took longer than the mount timeout, and was rolled back.
+    Timeout,
+    /// A mount that was supposed to be read-only came up read-write. Carries the mountpoint.
+    NotReadOnly(String),
+    /// The archive doesn't have a requested content folder. Carries the folder.
+    NoContentFolder(String),
+    /// unionfs exited successfully, but the union never showed up.
+    UnionNotMounted,
+    /// `BASE_DIR`, the first branch of every union, isn't there, so the union can't be remounted.
+    BaseDirMissing,
+    /// The device got as far as the union, but its files didn't show up there in time, so it was unmounted again.
+    /// Carries the path we looked for.
+    NotVisible(String),
+    /// A branch given to `/union/set` doesn't belong to any mounted device. Carries the branch.
+    BranchNotMounted(String),
+    /// A branch was given to `/union/set` more than once. Carries the branch.
+    DuplicateBranch(String),
+    /// A branch given to `/union/set` isn't a directory that's there. Carries the branch.
+    BranchMissing(String),
+    /// `/union/set` was given an order that leaves out one of a mounted device's branches. Carries the branch.
+    BranchLeftOut(String),
+    /// `/union/set` was given an order that moves a protected device's branch below another device's. Carries the
+    /// branch.
+    ProtectedBranchMoved(String),
+    /// The mount table couldn't be read.
+    MountTableUnreadable,
+    /// The query string is longer than we're willing to parse.
+    QueryTooLong,
+    /// The request came from an address that isn't on the client allow list.
+    ClientNotAllowed,
+    /// An admin endpoint was asked for, but no admin token is configured, so they're all turned off.
+    AdminDisabled,
+    /// An admin endpoint was asked for without the admin token.
+    Unauthorized,
+    /// The handler for the request panicked.
+    Internal,
+    /// Unmounting one or more layers failed. Carries each layer that failed, and how its umount did. However the
+    /// umounts failed, the host is to blame, so it's always a 503.
+    UnmountFailed(Vec<(&'static str, SubprocessError)>),
+}
+
+impl MountError {
+    /// The HTTP status for this error.
+    pub fn status(&self) -> u16 {
+        match self {
+            MountError::AlreadyMounted | MountError::NotMounted => 200,
+            MountError::InvalidParam(_)
+            | MountError::UnknownParams(_)
+            | MountError::InvalidDevname { .. }
+            | MountError::DeviceNotFound(_)
+            | MountError::DeviceIsDirectory { .. }
+            | MountError::DeviceDisappeared(_)
+            | MountError::BranchNotMounted(_)
+            | MountError::DuplicateBranch(_)
+            | MountError::BranchMissing(_)
+            | MountError::BranchLeftOut(_)
+            | MountError::ProtectedBranchMoved(_) => 400,
+            MountError::Unauthorized => 401,
+            MountError::Protected
+            | MountError::DeviceNotAllowed(_)
+            | MountError::ClientNotAllowed
+            | MountError::AdminDisabled => 403,
+            MountError::InProgress | MountError::DeviceInUse(_) | MountError::LockedElsewhere => {
+                409
+            }
+            // The archive is fine as far as we can tell, it just doesn't have what was asked for.
+            MountError::NoContentFolder(_) => 422,
+            // The download went through, but got something other than what was asked for.
+            MountError::ChecksumMismatch { .. } => 422,
+            MountError::DownloadTooLarge(_) | MountError::QueryTooLong => 413,
+            // Whoever serves the archive let us down, not the host.
+            MountError::DownloadFailed(_) => 502,
+            MountError::Timeout => 504,
+            // Whether the device is there is exactly what we couldn't find out. Blaming the client would be wrong.
+            MountError::DeviceStatFailed(..) => 500,
+            MountError::Internal => 500,
+            MountError::SubprocessFailed(err) => err.to_response().status,
+            // It's policy, not the host, so it doesn't get an environment_ code. It's just as temporary, though.
+            MountError::CapacityReached(_) | MountError::CircuitOpen(_) => 503,
+            MountError::DeviceLockFailed
+            | MountError::DeviceReadFailed(_)
+            | MountError::MountpointCreateFailed(_)
+            | MountError::MountpointRemoveFailed
+            | MountError::MountTableUnreadable
+            | MountError::StagingFailed(_)
+            | MountError::NotReadOnly(_)
+            | MountError::UnionNotMounted
+            | MountError::BaseDirMissing
+            | MountError::NotVisible(_)
+            | MountError::UnmountFailed(_) => 503,
+        }
+    }
+
+    /// A short machine-readable name for this error, sent in `X-Error-Code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            MountError::InvalidParam(_) => "invalid_param",
+            MountError::UnknownParams(_) => "unknown_params",
+            MountError::InvalidDevname { code, .. } => code,
+            MountError::DeviceNotFound(_) => "device_not_found",
+            MountError::DeviceIsDirectory { .. } => "device_is_directory",
+            MountError::DeviceStatFailed(..) => "device_stat_failed",
+            MountError::DeviceReadFailed(_) => "environment_device_read_failed",
+            MountError::DeviceDisappeared(_) => "device_disappeared",
+            MountError::AlreadyMounted => "already_mounted",
+            MountError::NotMounted => "not_mounted",
+            MountError::Protected => "protected_device",
+            MountError::DeviceNotAllowed(_) => "device_not_allowed",
+            MountError::InProgress => "in_progress",
+            MountError::DeviceInUse(_) => "device_in_use",
+            MountError::CapacityReached(_) => "capacity_reached",
+            MountError::CircuitOpen(_) => "circuit_open",
+            MountError::LockedElsewhere => "locked_elsewhere",
+            MountError::DeviceLockFailed => "environment_device_lock_failed",
+            MountError::MountpointCreateFailed(_) => "environment_mountpoint_create_failed",
+            MountError::MountpointRemoveFailed => "environment_mountpoint_remove_failed",
+            MountError::DownloadFailed(_) => "download_failed",
+            MountError::DownloadTooLarge(_) => "download_too_large",
+            MountError::ChecksumMismatch { .. } => "checksum_mismatch",
+            MountError::StagingFailed(_) => "environment_staging_failed",
+            MountError::SubprocessFailed(err) => err.to_response().code,
+            MountError::Timeout => "mount_timed_out",
+            MountError::NotReadOnly(_) => "environment_not_read_only",
+            MountError::NoContentFolder(_) => "no_content_folder",
+            MountError::UnionNotMounted => "environment_union_not_mounted",
+            MountError::BaseDirMissing => "environment_base_dir_missing",
+            MountError::NotVisible(_) => "environment_union_not_readable",
+            MountError::BranchNotMounted(_) => "branch_not_mounted",
+            MountError::DuplicateBranch(_) => "duplicate_branch",
+            MountError::BranchMissing(_) => "branch_missing",
+            MountError::BranchLeftOut(_) => "branch_left_out",
+            MountError::ProtectedBranchMoved(_) => "protected_branch_moved",
+            MountError::MountTableUnreadable => "environment_mount_table_unreadable",
+            MountError::QueryTooLong => "query_too_long",
+            MountError::ClientNotAllowed => "client_not_allowed",
+            MountError::AdminDisabled => "admin_disabled",
+            MountError::Unauthorized => "unauthorized",
+            MountError::Internal => "internal_error",
+            MountError::UnmountFailed(_) => "environment_unmount_failed",
+        }
+    }
+
+    /// A human-readable description of this error.
+    pub fn message(&self) -> String {
+        match self {
+            MountError::InvalidParam(message) => message.clone(),
+            MountError::UnknownParams(names) => "Unknown params: ".to_owned() + &names.join(", "),
+            MountError::InvalidDevname { message, .. } => message.clone(),
+            MountError::DeviceNotFound(devname) => {
+                "Requested device doesn't exist: ".to_owned() + devname
+            }
+            MountError::DeviceIsDirectory {
+                devname,
+                path,
+                file_type,
+            } => format!(
+                "Requested device is a directory : {} (resolved to {}, which is a {})",
+                devname, path, file_type
+            ),
+            MountError::DeviceStatFailed(devname, why) => {
+                format!("Could not stat requested device {}: {}", devname, why)
+            }
+            MountError::DeviceReadFailed(devname) => "Could not read device: ".to_owned() + devname,
+            MountError::DeviceDisappeared(devname) => {
+                "Requested device disappeared while mounting: ".to_owned() + devname
+            }
+            MountError::AlreadyMounted => "Device is already mounted.".to_owned(),
+            MountError::NotMounted => "Device is not mounted.".to_owned(),
+            MountError::Protected => "Device is protected and can't be unmounted.".to_owned(),
+            MountError::DeviceNotAllowed(path) => {
+                "Device isn't allowed to be mounted: ".to_owned() + path
+            }
+            MountError::InProgress => "Mount operation already in progress.".to_owned(),
+            MountError::DeviceInUse(path) => {
+                "Device is already in use under another devname: ".to_owned() + path
+            }
+            MountError::CapacityReached(max) => {
+                format!("Can't mount more than {} devices at once.", max)
+            }
+            MountError::CircuitOpen(remaining) => format!(
+                "Too many mounts have failed in a row, not mounting anything for another {}ms.",
+                remaining.as_millis()
+            ),
+            MountError::LockedElsewhere => "Device is locked by another process.".to_owned(),
+            MountError::DeviceLockFailed => "Could not lock device.".to_owned(),
+            MountError::MountpointCreateFailed(what) => format!("Could not create {}.", what),
+            MountError::MountpointRemoveFailed => "Could not remove mountpoints.".to_owned(),
+            MountError::DownloadFailed(what) => "Could not download archive: ".to_owned() + what,
+            MountError::DownloadTooLarge(limit) => {
+                format!("Archive is larger than the limit of {} bytes.", limit)
+            }
+            MountError::ChecksumMismatch { expected, actual } => format!(
+                "Downloaded archive has sha256 {}, expected {}.",
+                actual, expected
+            ),
+            MountError::StagingFailed(what) => "Could not stage archive: ".to_owned() + what,
+            MountError::SubprocessFailed(err) => err.to_response().body,
+            MountError::Timeout => "Mount took too long and was aborted.".to_owned(),
+            MountError::NotReadOnly(mountpt) => "Mount isn't read-only: ".to_owned() + mountpt,
+            MountError::NoContentFolder(subdir) if subdir == crate::DEFAULT_SUBDIR => {
+                "No content folder.".to_owned()
+            }
+            MountError::NoContentFolder(subdir) => "No such content folder: ".to_owned() + subdir,
+            MountError::UnionNotMounted => {
+                "unionfs exited successfully, but the union isn't mounted.".to_owned()
+            }
+            MountError::BaseDirMissing => {
+                format!(
+                    "{} is missing, so the union can't be remounted.",
+                    crate::BASE_DIR
+                )
+            }
+            MountError::NotVisible(path) => {
+                "Device didn't show up in the union in time, and was unmounted again: ".to_owned()
+                    + path
+            }
+            MountError::BranchNotMounted(key) => {
+                "Branch doesn't belong to a mounted device: ".to_owned() + key
+            }
+            MountError::DuplicateBranch(key) => {
+                "Branch is listed more than once: ".to_owned() + key
+            }
+            MountError::BranchMissing(key) => "Branch path doesn't exist: ".to_owned() + key,
+            MountError::BranchLeftOut(key) => {
+                "Branch of a mounted device is missing from the list: ".to_owned() + key
+            }
+            MountError::ProtectedBranchMoved(key) => {
+                "Protected branches have to come before all the others: ".to_owned() + key
+            }
+            MountError::MountTableUnreadable => {
+                format!("Could not read {}.", crate::mounts::MOUNT_TABLE)
+            }
+            MountError::QueryTooLong => "Query string is too long.".to_owned(),
+            MountError::ClientNotAllowed => "Requests from this address aren't allowed.".to_owned(),
+            MountError::AdminDisabled => "Admin endpoints are disabled.".to_owned(),
+            MountError::Unauthorized => "Missing or invalid admin token.".to_owned(),
+            MountError::Internal => "Internal error.".to_owned(),
+            MountError::UnmountFailed(failures) => {
+                let details: Vec<String> = failures
+                    .iter()
+                    .map(|(layer, err)| format!("{}: {}", layer, err.to_response().body))
+                    .collect();
+                "Could not unmount ".to_owned() + &details.join("; ")
+            }
+        }
+    }
+
+    /// Whether this is the host letting us down, rather than the request, the device or our own policy. Only these
+    /// count towards tripping the circuit breaker.
+    pub fn is_host_failure(&self) -> bool {
+        matches!(
+            self,
+            MountError::DeviceLockFailed
+                | MountError::DeviceReadFailed(_)
+                | MountError::MountpointCreateFailed(_)
+                | MountError::MountpointRemoveFailed
+                | MountError::MountTableUnreadable
+                | MountError::StagingFailed(_)
+                | MountError::SubprocessFailed(_)
+                | MountError::Timeout
+                | MountError::NotReadOnly(_)
+                | MountError::UnionNotMounted
+                | MountError::BaseDirMissing
+                | MountError::NotVisible(_)
+                | MountError::UnmountFailed(_)
+        )
+    }
+
+    /// The response to send a client whose request failed because of this.
+    pub fn to_response(&self) -> HTTPResponse {
+        HTTPResponse {
+            status: self.status(),
+            code: self.code(),
+            body: self.message(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn not_really_failures_are_200s() {
+        for err in [MountError::AlreadyMounted, MountError::NotMounted] {
+            assert_eq!(err.status(), 200);
+            assert!(!err.is_host_failure());
+        }
+        assert_eq!(MountError::AlreadyMounted.code(), "already_mounted");
+        assert_eq!(MountError::NotMounted.code(), "not_mounted");
+    }
+
+    #[test]
+    fn conflicts_are_409s() {
+        let conflicts = [
+            (MountError::InProgress, "in_progress"),
+            (
+                MountError::DeviceInUse("/dev/sdb".to_owned()),
+                "device_in_use",
+            ),
+            (MountError::LockedElsewhere, "locked_elsewhere"),
+        ];
+        for (err, code) in conflicts {
+            assert_eq!(err.status(), 409);
+            assert_eq!(err.code(), code);
+            assert!(!err.is_host_failure());
+        }
+    }
+
+    #[test]
+    fn host_failures_are_503s_with_environment_codes() {
+        let failures = [
+            MountError::DeviceLockFailed,
+            MountError::DeviceReadFailed("sdb".to_owned()),
+            MountError::MountpointCreateFailed("the fuzzyfs mountpoint"),
+            MountError::MountpointRemoveFailed,
+            MountError::MountTableUnreadable,
+            MountError::StagingFailed("disk full".to_owned()),
+            MountError::NotReadOnly("/tmp/sdb/zip".to_owned()),
+            MountError::UnionNotMounted,
+            MountError::BaseDirMissing,
+            MountError::NotVisible("/var/www/localhost/htdocs/x".to_owned()),
+        ];
+        for err in failures {
+            assert_eq!(err.status(), 503, "{}", err.code());
+            assert!(err.code().starts_with("environment_"), "{}", err.code());
+            assert!(err.is_host_failure(), "{}", err.code());
+        }
+    }
+
+    #[test]
+    fn policy_503s_arent_host_failures() {
+        let refusals = [
+            (MountError::CapacityReached(4), "capacity_reached"),
+            (
+                MountError::CircuitOpen(Duration::from_secs(1)),
+                "circuit_open",
+            ),
+        ];
+        for (err, code) in refusals {
+            assert_eq!(err.status(), 503);
+            assert_eq!(err.code(), code);
+            assert!(!err.is_host_failure());
+        }
+    }
+
+    #[test]
+    fn refused_requests_keep_their_own_statuses() {
+        let refusals = [
+            (MountError::Unauthorized, 401, "unauthorized"),
+            (MountError::AdminDisabled, 403, "admin_disabled"),
+            (MountError::ClientNotAllowed, 403, "client_not_allowed"),
+            (MountError::QueryTooLong, 413, "query_too_long"),
+            (MountError::Internal, 500, "internal_error"),
+        ];
+        for (err, status, code) in refusals {
+            assert_eq!((err.status(), err.code()), (status, code));
+            assert!(!err.is_host_failure());
+        }
+    }
+
+    #[test]
+    fn unmount_failures_are_always_503s() {
+        let failures = [
+            vec![("fuzzyfs", SubprocessError::NonZeroExit { code: Some(32) })],
+            vec![
+                ("fuzzyfs", SubprocessError::TimedOut),
+                (
+                    "fuse-archive",
+                    SubprocessError::NonZeroExit { code: Some(32) },
+                ),
+            ],
+        ];
+        for failures in failures {
+            let err = MountError::UnmountFailed(failures);
+            assert_eq!(err.status(), 503);
+            assert_eq!(err.code(), "environment_unmount_failed");
+            assert!(err.is_host_failure());
+        }
+    }
+}
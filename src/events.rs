@@ -0,0 +1,112 @@
+use crate::{util::format_timestamp, LockedMountStatus};
+use log::{debug, error, info, warn};
+use serde_json::{json, Value};
+use std::{hash::BuildHasher, sync::Arc, time::SystemTime};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{UnixListener, UnixStream},
+    signal::unix::{signal, SignalKind},
+    sync::broadcast::{self, error::RecvError},
+};
+
+/// How many events a slow listener can fall behind by before it starts missing some.
+const BACKLOG: usize = 256;
+
+/// Operations starting, changing phase and finishing, for local listeners to follow as they happen.
+pub struct Events {
+    sender: broadcast::Sender<String>,
+}
+
+impl Default for Events {
+    fn default() -> Events {
+        Events {
+            sender: broadcast::channel(BACKLOG).0,
+        }
+    }
+}
+
+impl Events {
+    /// Sends `event`, with the time added, to everyone listening. Nobody listening is fine.
+    pub fn publish(&self, mut event: Value) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        if let Value::Object(fields) = &mut event {
+            fields.insert(
+                "timestamp".to_owned(),
+                format_timestamp(SystemTime::now()).into(),
+            );
+        }
+        let _ = self.sender.send(event.to_string());
+    }
+}
+
+/// Streams events as newline-delimited JSON to whoever connects to the unix socket at `path`, until we're told to
+/// stop, when the socket is removed. One left behind by a daemon that didn't get to remove it is replaced.
+pub async fn serve_socket<T: BuildHasher + Send + Sync + 'static>(
+    path: String,
+    shared_state: Arc<LockedMountStatus<T>>,
+) {
+    let _ = std::fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Could not listen on {}, not sending events: {}", path, err);
+            return;
+        }
+    };
+    info!("Sending events on {}", path);
+    let (mut terminate, mut interrupt) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        _ => {
+            warn!(
+                "Could not listen for signals, {} won't be removed on exit",
+                path
+            );
+            loop {
+                accept(&listener, &shared_state).await;
+            }
+        }
+    };
+    loop {
+        tokio::select! {
+            _ = accept(&listener, &shared_state) => {}
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
+    }
+    // Handling the signal means it no longer stops us by itself.
+    let _ = std::fs::remove_file(&path);
+    std::process::exit(0);
+}
+
+/// Takes the next connection, and starts sending it events.
+async fn accept<T: BuildHasher>(listener: &UnixListener, shared_state: &Arc<LockedMountStatus<T>>) {
+    match listener.accept().await {
+        Ok((stream, _)) => {
+            tokio::spawn(send_events(stream, shared_state.events.sender.subscribe()));
+        }
+        Err(err) => warn!("Could not accept an event listener: {}", err),
+    }
+}
+
+/// Writes each event to `stream` as a line, until it goes away.
+async fn send_events(mut stream: UnixStream, mut events: broadcast::Receiver<String>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            // Better that it knows it missed some than that it's left guessing.
+            Err(RecvError::Lagged(missed)) => {
+                debug!("An event listener fell behind and missed {} events", missed);
+                json!({ "event": "lagged", "missed": missed }).to_string()
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if stream.write_all((event + "\n").as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
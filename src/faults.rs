@@ -0,0 +1,74 @@
+use crate::subprocess::SubprocessError;
+use fnv::{FnvHashMap, FnvHashSet};
+use parking_lot::Mutex;
+
+/// A step of a mount that a fault can be injected into.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Mounting the archive, with fuse-archive or squashfuse.
+    Archive,
+    Fuzzyfs,
+    /// Remounting the union with the new device in it.
+    Union,
+}
+
+impl Stage {
+    /// Parses the `stage` query param.
+    pub fn parse(value: &str) -> Option<Stage> {
+        match value {
+            "fuse-archive" => Some(Stage::Archive),
+            "fuzzyfs" => Some(Stage::Fuzzyfs),
+            "union" => Some(Stage::Union),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the `code` query param: the name of the subprocess failure to pretend happened, as in `SubprocessError::code`.
+pub fn parse_code(value: &str) -> Option<SubprocessError> {
+    match value {
+        "spawn_failed" => Some(SubprocessError::SpawnFailed),
+        "wait_failed" => Some(SubprocessError::WaitFailed),
+        "nonzero_exit" => Some(SubprocessError::NonZeroExit { code: Some(1) }),
+        "timed_out" => Some(SubprocessError::TimedOut),
+        _ => None,
+    }
+}
+
+/// Failures that the next mount of a devname should run into, for testing clients against. Only built for tests or
+/// with the `test-hooks` feature.
+#[derive(Default)]
+pub struct Faults {
+    pending: Mutex<FnvHashMap<String, (Stage, SubprocessError)>>,
+    /// Device dirs whose mounts should look dead to the stale mount check.
+    stale: Mutex<FnvHashSet<String>>,
+}
+
+impl Faults {
+    /// Makes the next mount of `devname` fail at `stage` with `err`, replacing any fault already set up for it.
+    pub fn inject(&self, devname: String, stage: Stage, err: SubprocessError) {
+        self.pending.lock().insert(devname, (stage, err));
+    }
+
+    /// Uses up the fault for `devname`, if it's for `stage`.
+    pub fn take(&self, devname: &str, stage: Stage) -> Option<SubprocessError> {
+        let mut pending = self.pending.lock();
+        match pending.get(devname) {
+            Some((at, _)) if *at == stage => pending.remove(devname).map(|(_, err)| err),
+            _ => None,
+        }
+    }
+
+    /// Makes every mount of `devname` look like its fuse process has gone.
+    pub fn mark_stale(&self, devname: &str) {
+        self.stale.lock().insert(crate::device_dir(devname) + "/");
+    }
+
+    /// Whether `path` is in a device dir marked stale.
+    pub fn is_stale(&self, path: &str) -> bool {
+        self.stale
+            .lock()
+            .iter()
+            .any(|dir| path.starts_with(dir.as_str()))
+    }
+}
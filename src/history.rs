@@ -0,0 +1,76 @@
+use crate::util::format_timestamp;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use std::{collections::VecDeque, time::SystemTime};
+
+/// One finished mount or unmount.
+struct Record {
+    timestamp: SystemTime,
+    operation: &'static str,
+    devname: String,
+    status: u16,
+    code: &'static str,
+    duration_ms: u64,
+}
+
+/// The last few mounts and unmounts, oldest first, for a quick look at what's been going on.
+/// It only lives in memory, so it starts over whenever the daemon does.
+pub struct History {
+    records: Mutex<VecDeque<Record>>,
+    capacity: usize,
+}
+
+impl History {
+    /// An empty history that keeps the last `capacity` operations. A capacity of 0 keeps nothing.
+    pub fn new(capacity: usize) -> History {
+        History {
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Adds a finished operation, forgetting the oldest one if there's no room.
+    pub fn record(
+        &self,
+        operation: &'static str,
+        devname: &str,
+        status: u16,
+        code: &'static str,
+        duration_ms: u64,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock();
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        records.push_back(Record {
+            timestamp: SystemTime::now(),
+            operation,
+            devname: devname.to_owned(),
+            status,
+            code,
+            duration_ms,
+        });
+    }
+
+    /// Every operation we still remember, oldest first, for `/history`.
+    pub fn to_json(&self) -> Value {
+        let records = self.records.lock();
+        records
+            .iter()
+            .map(|record| {
+                json!({
+                    "timestamp": format_timestamp(record.timestamp),
+                    "operation": record.operation,
+                    "devname": record.devname,
+                    "success": record.status < 400,
+                    "status": record.status,
+                    "code": record.code,
+                    "duration_ms": record.duration_ms,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,72 @@
+use std::time::Instant;
+
+/// The step an in-flight mount or unmount is currently on.
+#[derive(Clone, Copy)]
+pub enum Phase {
+    Preparing,
+    WaitingForSlot,
+    CreatingMountpoints,
+    MountingArchive,
+    MountingFuzzyfs,
+    CheckingContent,
+    AcquiringUnionLock,
+    RemountingUnion,
+    /// Answered, and waiting for a coalesced union rebuild to take the device's branches out.
+    AwaitingRebuild,
+    UnmountingFuzzyfs,
+    UnmountingArchive,
+    RemovingMountpoints,
+}
+
+impl Phase {
+    /// A short machine-readable name for this phase.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Preparing => "preparing",
+            Phase::WaitingForSlot => "waiting_for_slot",
+            Phase::CreatingMountpoints => "creating_mountpoints",
+            Phase::MountingArchive => "mounting_archive",
+            Phase::MountingFuzzyfs => "mounting_fuzzyfs",
+            Phase::CheckingContent => "checking_content",
+            Phase::AcquiringUnionLock => "acquiring_union_lock",
+            Phase::RemountingUnion => "remounting_union",
+            Phase::AwaitingRebuild => "awaiting_rebuild",
+            Phase::UnmountingFuzzyfs => "unmounting_fuzzyfs",
+            Phase::UnmountingArchive => "unmounting_archive",
+            Phase::RemovingMountpoints => "removing_mountpoints",
+        }
+    }
+}
+
+/// Tracks an operation that's marked as changing a device, so that a hang can be pinned on a phase.
+pub struct InFlight {
+    /// `mount`, `umount`, `replace` or `evict`.
+    pub operation: &'static str,
+    pub phase: Phase,
+    /// When the operation started.
+    pub started: Instant,
+    /// When the operation entered its current phase.
+    pub phase_started: Instant,
+    /// The resolved path of the device it's working on, once that's known.
+    pub device: Option<String>,
+}
+
+impl InFlight {
+    /// Starts tracking an operation in its first phase.
+    pub fn new(operation: &'static str, phase: Phase) -> InFlight {
+        let now = Instant::now();
+        InFlight {
+            operation,
+            phase,
+            started: now,
+            phase_started: now,
+            device: None,
+        }
+    }
+
+    /// Moves on to the next phase.
+    pub fn enter(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.phase_started = Instant::now();
+    }
+}
@@ -0,0 +1,37 @@
+use crate::util::format_timestamp;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::time::SystemTime;
+
+/// Writes each log record to stderr as a single `timestamp level target: message` line.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // The log macros check this themselves, but records bridged from tracing don't.
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        eprintln!(
+            "{} {:<5} {}: {}",
+            format_timestamp(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Installs the stderr logger, showing records up to `level`.
+pub fn init(level: LevelFilter) {
+    // This only fails if a logger is already installed, in which case that one wins.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
@@ -1,60 +1,463 @@
-use std::{collections::HashSet, hash::BuildHasher, sync::Arc};
+// The effective config is one big json! invocation, which outgrows the default limit.
+#![recursion_limit = "256"]
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    future::Future,
+    hash::BuildHasher,
+    io::ErrorKind,
+    ops::{Deref, DerefMut},
+    path::{Component, Path},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
 use fnv::{FnvHashMap, FnvHashSet};
-use parking_lot::Mutex;
-use tokio::fs::{create_dir_all, metadata, remove_dir};
+use log::{error, info, warn, LevelFilter};
+use parking_lot::{Mutex, RwLock};
+use serde_json::{json, Value};
+use tokio::fs::{canonicalize, create_dir_all, metadata, read_dir, remove_dir, symlink_metadata};
 use tokio::join;
 use tokio::process::{Child, Command};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{sleep, timeout_at, Instant};
 use warp::Filter;
 
+mod breaker;
+mod capability;
+mod cleanup;
+mod config;
+mod download;
+mod error;
+mod events;
+#[cfg(any(test, feature = "test-hooks"))]
+mod faults;
+mod history;
+mod inflight;
+mod logger;
+mod metrics;
+mod mounts;
+mod overlap;
+mod priority;
+mod probe;
+mod retry;
+mod server;
+mod sha256;
+mod subprocess;
+#[cfg(test)]
+mod tests;
 mod util;
-use util::handle_devname;
+mod version;
+mod warm;
+mod webhook;
+use breaker::Breaker;
+use capability::AllowOther;
+use config::{Config, StaleUnmount};
+use download::{download, StagedFile};
+use error::MountError;
+use events::Events;
+#[cfg(any(test, feature = "test-hooks"))]
+use faults::{Faults, Stage};
+use history::History;
+use inflight::{InFlight, Phase};
+use metrics::Metrics;
+use mounts::{
+    is_live_fuse_mount, is_mountpoint, is_read_only, is_stale, live_union_branches, read_mounts,
+    replace_mount, wait_unmounted,
+};
+use priority::{Priority, PrioritySemaphore};
+use probe::{detect_format, Format};
+use retry::retry_with_backoff;
+use subprocess::{capture_output, log_stderr, privileged, wait_subprocess, SubprocessError};
+use util::{
+    check_auth, client_allowlist, format_timestamp, handle_devname, handle_devname_pair,
+    handle_rejection, is_not_found, json_headers, lock_device, parse_bool_param,
+    query_length_limit, stat_device, to_response, versioned,
+};
+use warm::WarmProgress;
 
-const DEV_LOCATION: &str = if cfg!(feature = "docker") {
+// Tests make their own devices, somewhere they're allowed to.
+const DEV_LOCATION: &str = if cfg!(test) {
+    "/tmp/fpvm-test-dev/"
+} else if cfg!(feature = "docker") {
     "/mnt/docker/"
 } else {
     "/dev/"
 };
 
-const UNIONFS_MOUNTPT: &str = "/var/www/localhost/htdocs";
-const BASE_DIR: &str = "/root/base";
+// Tests build their unions somewhere they can't get in the way of a real one.
+const UNIONFS_MOUNTPT: &str = if cfg!(test) {
+    "/tmp/fpvm-test-htdocs"
+} else {
+    "/var/www/localhost/htdocs"
+};
+// Where the next union gets built when swapping atomically, before it's moved onto UNIONFS_MOUNTPT.
+const UNIONFS_SHADOW_MOUNTPT: &str = "/tmp/union.next";
+const BASE_DIR: &str = if cfg!(test) {
+    "/tmp/fpvm-test-base"
+} else {
+    "/root/base"
+};
+// Where each device's fuse mountpoints go.
+const TMP_DIR: &str = "/tmp/";
+// The port we serve on, on localhost only.
+const PORT: u16 = 3030;
+
+// Fault injection is for testing clients against, and must never ship.
+#[cfg(all(feature = "test-hooks", not(debug_assertions)))]
+compile_error!("The test-hooks feature can't be used in release builds.");
 
 // Binary paths, hard-coded for alpine. Modify to taste.
 const FUSE_ARCHIVE: &str = "/usr/local/bin/fuse-archive";
+const SQUASHFUSE: &str = "/usr/bin/squashfuse";
 const FUZZYFS: &str = "/usr/local/bin/fuzzyfs";
+const MOUNT: &str = "/bin/mount";
 const UMOUNT: &str = "/bin/umount";
+const FUSERMOUNT: &str = "/usr/bin/fusermount";
 const UNIONFS: &str = "/usr/bin/unionfs";
 
+// The folder inside an archive that gets served, unless a client asks for others.
+const DEFAULT_SUBDIR: &str = "content";
+
+// The query params each devname endpoint understands. Anything else is refused in strict mode.
+const MOUNT_PARAMS: &[&str] = &[
+    "devname", "wait_ms", "subdirs", "raw", "ro", "settle", "url", "sha256", "format", "verbose",
+    "async", "priority",
+];
+const REPLACE_PARAMS: &[&str] = &[
+    "old", "new", "wait_ms", "subdirs", "raw", "ro", "settle", "url", "sha256", "format",
+    "priority",
+];
+const UMOUNT_PARAMS: &[&str] = &["devname", "verbose", "async"];
+const DEVNAME_PARAMS: &[&str] = &["devname"];
+#[cfg(any(test, feature = "test-hooks"))]
+const FAULT_PARAMS: &[&str] = &["devname", "stage", "code"];
+
+// How often to check for a device that hasn't shown up yet, and the longest a client may ask us to wait for one.
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_DEVICE_WAIT_MS: u64 = 60_000;
+// How often to check for paths we need at startup.
+const STARTUP_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// How many devices' last failures we remember at most. Bounds memory if clients send lots of bogus devnames.
+const MAX_FAILURES: usize = 256;
+
 pub struct HTTPResponse {
     status: u16,
+    /// A short machine-readable name for the outcome, sent as `X-Error-Code` on failures.
+    code: &'static str,
     body: String,
 }
 
 struct MountStatus<T: BuildHasher> {
-    mounted: HashSet<String, T>,
-    changing: HashSet<String, T>,
+    /// Mounted devices, keyed by content key. The content key identifies a device even if its `content` folder isn't a branch.
+    mounted: HashMap<String, MountEntry, T>,
+    /// Devices that a mount or unmount is currently working on, keyed by content key.
+    changing: HashMap<String, InFlight, T>,
+    /// The union branches below `BASE_DIR`, in order. Normally every mounted device's branches, newest first.
+    branches: Vec<String>,
+    /// Why the last mount of each devname failed. Cleared when that devname mounts successfully.
+    failures: HashMap<String, Failure, T>,
+}
+
+/// What we know about a mounted device.
+struct MountEntry {
+    /// The devname it was mounted with. Don't try to reconstruct this from the content key.
+    devname: String,
+    /// Where the devname led when it was mounted, with symlinks resolved. Other devnames for the same device are refused.
+    device: String,
+    /// This device's union branches: one folder inside its fuzzyfs mount per requested subdir.
+    branches: Vec<String>,
+    /// When the mount finished.
+    mounted_at: SystemTime,
+    /// Whether the device was mounted directly, rather than through fuse-archive.
+    raw: bool,
+    /// The flock on the device node, if device locking is on. Never read, it just has to stay alive.
+    _device_lock: Option<File>,
+    /// The downloaded archive, if it was mounted from a url. Never read, it's deleted when dropped.
+    _staged: Option<StagedFile>,
+    /// How far cache warming has got, if it's on.
+    warm: Option<Arc<WarmProgress>>,
+    /// Whether its files have been seen through the union. Only false while a settling mount is still waiting for them.
+    visible: bool,
+    /// Whether its branches are still waiting for a coalesced rebuild to put them in the union.
+    pending_rebuild: bool,
+}
+
+impl MountEntry {
+    /// Whether the device can be used yet: it's in the union and has showed up there, and warming has finished if
+    /// it's on.
+    fn is_ready(&self) -> bool {
+        !self.pending_rebuild
+            && self.visible
+            && self.warm.as_ref().is_none_or(|warm| warm.is_done())
+    }
+}
+
+/// A failed mount, kept around so that clients can find out about it after the fact.
+struct Failure {
+    code: &'static str,
+    message: String,
+    timestamp: SystemTime,
 }
 
 pub struct LockedMountStatus<T: BuildHasher> {
     status: Mutex<MountStatus<T>>,
+    // Take this through lock_union, which keeps track of how long everyone waits for it and holds it.
+    // tokio's Mutex queues waiters in FIFO order, so nobody can be starved by later arrivals.
     union: tokio::sync::Mutex<i32>,
+    // Take this through config(). SIGHUP swaps in a new one, so hold on to what it returns for as long as you need
+    // a consistent view.
+    config: RwLock<Arc<Config>>,
+    metrics: Metrics,
+    // Limits how many mounts run at once, if there's a limit.
+    mount_slots: Option<PrioritySemaphore>,
+    // The same for unmounts. They don't have priorities, so they're all normal.
+    umount_slots: Option<PrioritySemaphore>,
+    // The last few mounts and unmounts, for /history.
+    history: History,
+    // Operations starting and finishing, for the event socket.
+    events: Events,
+    // Operations waiting for a coalesced union rebuild, if there's a quiet period.
+    rebuilds: Mutex<Rebuilds>,
+    // Turns mounts away after too many host failures in a row, if there's a threshold.
+    breaker: Breaker,
+    // What the unionfs binary said its version was at startup.
+    unionfs_version: Option<String>,
+    // Whether that unionfs said it can change a live union's branches with `-o remount`.
+    union_remount: bool,
+    // Whether it looked at startup like we're allowed to mount at all.
+    mount_capable: bool,
+    // Whether BASE_DIR was there the last time we looked, which is at startup and before every union remount.
+    base_dir_present: AtomicBool,
+    // Whether it looked at startup like the fuse mounts can use allow_other.
+    allow_other: AllowOther,
+    // Failures that tests have asked for.
+    #[cfg(any(test, feature = "test-hooks"))]
+    faults: Faults,
+}
+
+impl<T: BuildHasher> LockedMountStatus<T> {
+    /// The configuration as it is right now.
+    fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read())
+    }
+}
+
+/// What we were asked to do on the command line.
+struct Args {
+    /// The config file to read, from `--config <path>` (or `--config=<path>`), or else `FPVM_CONFIG`.
+    config: Option<String>,
+    /// `--cleanup`: unmount everything and exit, rather than serving.
+    cleanup: bool,
+}
+
+impl Args {
+    /// Parses the command line. Fails on anything it doesn't know.
+    fn parse() -> Result<Args, String> {
+        let mut parsed = Args {
+            config: None,
+            cleanup: false,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            if arg == "--config" {
+                parsed.config = Some(args.next().ok_or("--config needs a path")?);
+            } else if let Some(path) = arg.strip_prefix("--config=") {
+                parsed.config = Some(path.to_owned());
+            } else if arg == "--cleanup" {
+                parsed.cleanup = true;
+            } else {
+                return Err(format!("Unknown argument: {}", arg));
+            }
+        }
+        if parsed.config.is_none() {
+            parsed.config = std::env::var("FPVM_CONFIG")
+                .ok()
+                .filter(|path| !path.is_empty());
+        }
+        Ok(parsed)
+    }
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
+    // Resolve the configuration first, it decides how much we log and how big the runtime is.
+    // Made absolute, so that it can still be found for reloading once we've moved to /.
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(err) => {
+            logger::init(LevelFilter::Info);
+            error!("{}", err);
+            std::process::exit(2);
+        }
+    };
+    let path = args.config.map(|path| match std::fs::canonicalize(&path) {
+        Ok(absolute) => absolute.to_string_lossy().into_owned(),
+        Err(_) => path,
+    });
+    let config = match Config::load(path.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            logger::init(LevelFilter::Info);
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    logger::init(config.log_level);
+    // If we were started from somewhere under one of the mountpoints we manage (which happens in some containers),
+    // our working directory alone keeps it busy, and its unmounts fail with nothing visibly using it. Nothing we do
+    // needs a working directory, so move out of the way. The config file's path was made absolute before this.
+    if let Err(err) = std::env::set_current_dir("/") {
+        warn!("Could not change to /: {}", err);
+    }
+    // Better to refuse to start than to fail every mount later.
+    if let Err(err) = config.validate() {
+        error!("Invalid configuration: {}", err);
+        std::process::exit(1);
+    }
+
+    // This has to happen while we're still the only thread.
+    if let Some(namespace) = &config.mount_namespace {
+        if let Err(err) = mounts::enter_mount_namespace(namespace) {
+            error!("Could not enter the mount namespace {}: {}", namespace, err);
+            std::process::exit(1);
+        }
+        info!("Mounting in the namespace {}", namespace);
+    }
+
+    // Build the runtime by hand rather than with #[tokio::main], so that the worker count is configurable.
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(threads) = config.worker_threads {
+        builder.worker_threads(threads);
+    }
+    match builder.build() {
+        // For shutdown scripts: clean up after whichever daemon ran, and exit.
+        Ok(runtime) if args.cleanup => {
+            if !runtime.block_on(cleanup::cleanup(&config, PORT)) {
+                std::process::exit(1);
+            }
+        }
+        Ok(runtime) => runtime.block_on(serve(config, path)),
+        Err(err) => {
+            error!("Could not start the tokio runtime: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Sets up the shared state and the routes, and serves them until the process exits. `path` is the config file
+/// the configuration came from, if any, for reloading it.
+async fn serve(mut config: Config, path: Option<String>) {
+    // On boot, base and the web root may live on mounts that aren't up yet. Give them a moment,
+    // rather than failing the first requests.
+    let union_parent = Path::new(UNIONFS_MOUNTPT)
+        .parent()
+        .unwrap_or_else(|| Path::new("/"));
+    wait_for_paths(&[Path::new(BASE_DIR), union_parent], config.startup_wait).await;
+    // Base is the first branch of every union, so without it nothing can be mounted. It may still turn up, though.
+    let base_dir_present = is_base_dir_present().await;
+    if !base_dir_present {
+        error!(
+            "{} isn't a directory, no mounts will work until it is",
+            BASE_DIR
+        );
+    }
+    // An old unionfs fails in confusing ways, so find out what we've got before anything needs it.
+    let unionfs_version = version::check_unionfs(UNIONFS).await;
+    // The same goes for options it doesn't understand. If it won't say which it does, all we can do is hope.
+    let supported = version::unionfs_options(UNIONFS).await;
+    if let Some(supported) = &supported {
+        if let Err(err) = config.restrict_unionfs_options(supported) {
+            error!("Invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    }
+    // A unionfs that can swap a live union's branches saves us tearing it down for every mount.
+    let union_remount =
+        supported.is_some_and(|supported| supported.iter().any(|option| option == "remount"));
+    if union_remount {
+        info!("unionfs supports remount, changing the union's branches in place");
+    }
+    // Likewise, a daemon that can't mount should say so now, rather than with every request.
+    let mount_capable = capability::check_mount(&config.priv_wrapper);
+    let allow_other = capability::check_allow_other(&config.priv_wrapper);
+
     // Create a new status variable to maintain consistency.
     let mount_status = LockedMountStatus {
         status: Mutex::new(MountStatus {
-            mounted: FnvHashSet::default(),
-            changing: FnvHashSet::default(),
+            mounted: FnvHashMap::default(),
+            changing: FnvHashMap::default(),
+            branches: Vec::new(),
+            failures: FnvHashMap::default(),
         }),
         union: tokio::sync::Mutex::new(0),
+        mount_slots: config.max_concurrent_mounts.map(PrioritySemaphore::new),
+        umount_slots: config.max_concurrent_umounts.map(PrioritySemaphore::new),
+        history: History::new(config.history_size),
+        events: Events::default(),
+        rebuilds: Mutex::default(),
+        breaker: Breaker::default(),
+        config: RwLock::new(Arc::new(config)),
+        metrics: Metrics::default(),
+        unionfs_version,
+        union_remount,
+        mount_capable,
+        base_dir_present: AtomicBool::new(base_dir_present),
+        allow_other,
+        #[cfg(any(test, feature = "test-hooks"))]
+        faults: Faults::default(),
     };
 
+    // Crashed runs leave their device dirs behind. Nothing is mounted yet, so any that are empty are cruft.
+    if mount_status.config().sweep_tmp {
+        cleanup::sweep_tmp().await;
+    }
+
     // Atomically reference-count the status variable, so that it can have thread-safe multiple ownership.
     let global_state = Arc::new(mount_status);
-    // Turns out we need a reference-counted "clone" of it for the second path.
+    // The protected devices go in before anyone else gets a chance, so that they end up right after base.
+    mount_protected(&global_state).await;
+    // Pick up config changes without losing the mounts.
+    tokio::spawn(reload_on_hangup(Arc::clone(&global_state), path));
+    if let Some(socket) = global_state.config().event_socket.clone() {
+        tokio::spawn(events::serve_socket(socket, Arc::clone(&global_state)));
+    }
+    // Keep an eye out for branches whose fuse process has died.
+    if let Some(interval) = global_state.config().branch_check_interval {
+        tokio::spawn(watch_branches(Arc::clone(&global_state), interval));
+    }
+    // Turns out we need a reference-counted "clone" of it for each of the other paths.
     let global_state_clone = Arc::clone(&global_state);
+    let global_state_union = Arc::clone(&global_state);
+    let global_state_replace = Arc::clone(&global_state);
+    let global_state_metrics = Arc::clone(&global_state);
+    let global_state_status = Arc::clone(&global_state);
+    let global_state_list = Arc::clone(&global_state);
+    let global_state_tree = Arc::clone(&global_state);
+    let global_state_inflight = Arc::clone(&global_state);
+    let global_state_probe = Arc::clone(&global_state);
+    let global_state_verify = Arc::clone(&global_state);
+    let global_state_orphans = Arc::clone(&global_state);
+    let global_state_resolve = Arc::clone(&global_state);
+    let global_state_consistency = Arc::clone(&global_state);
+    let global_state_clients = Arc::clone(&global_state);
+    let global_state_history = Arc::clone(&global_state);
+    let global_state_version = Arc::clone(&global_state);
+    let global_state_config = Arc::clone(&global_state);
+    let global_state_health = Arc::clone(&global_state);
+    let global_state_listen = Arc::clone(&global_state);
+    let global_state_requests = Arc::clone(&global_state);
+    #[cfg(any(test, feature = "test-hooks"))]
+    let global_state_faults = Arc::clone(&global_state);
+    // The size limits are needed while building the routes.
+    let max_query_bytes = global_state.config().max_query_bytes;
+    let max_body_bytes = global_state.config().max_body_bytes;
 
     // Create the "/mount" route.
     let mount = warp::path("mount")
@@ -62,389 +465,3095 @@ async fn main() {
         .and(warp::path::end())
         // It takes a GET param.
         .and(warp::query::<FnvHashMap<String, String>>())
+        // Clients can ask for JSON instead of plaintext.
+        .and(warp::header::optional::<String>("accept"))
         // We use and_then instead of map, because this needs async capabilities.
-        .and_then(move |map: FnvHashMap<String, String>| {
-            // Increase the refcount for the global state.
-            let shared_state = Arc::clone(&global_state);
+        .and_then(
+            move |map: FnvHashMap<String, String>, accept: Option<String>| {
+                // Increase the refcount for the global state.
+                let shared_state = Arc::clone(&global_state);
+                async move {
+                    handle_devname(
+                        shared_state,
+                        map,
+                        accept,
+                        "mount",
+                        MOUNT_PARAMS,
+                        mount_request,
+                    )
+                    .await
+                }
+            },
+        );
+    // Pretty much the same as the previous one, not going to repeat all the comments.
+    let umount = warp::path("umount")
+        .and(warp::path::end())
+        .and(warp::query::<FnvHashMap<String, String>>())
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(
+            move |map: FnvHashMap<String, String>, accept: Option<String>| {
+                let shared_state = Arc::clone(&global_state_clone);
+                async move {
+                    handle_devname(
+                        shared_state,
+                        map,
+                        accept,
+                        "umount",
+                        UMOUNT_PARAMS,
+                        umount_request,
+                    )
+                    .await
+                }
+            },
+        );
+    // The "/replace" route, for swapping one device for another without a gap in between.
+    let replace = warp::post()
+        .and(warp::path("replace"))
+        .and(warp::path::end())
+        .and(warp::query::<FnvHashMap<String, String>>())
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(
+            move |map: FnvHashMap<String, String>, accept: Option<String>| {
+                let shared_state = Arc::clone(&global_state_replace);
+                async move {
+                    handle_devname_pair(
+                        shared_state,
+                        map,
+                        accept,
+                        "replace",
+                        ("old", "new"),
+                        REPLACE_PARAMS,
+                        replace_device,
+                    )
+                    .await
+                }
+            },
+        );
+    // The admin "/union/set" route. It takes an ordered JSON array of content keys.
+    let union_set = warp::post()
+        .and(warp::path!("union" / "set"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(max_body_bytes))
+        .and(warp::body::json::<Vec<String>>())
+        .and_then(move |auth: Option<String>, branches: Vec<String>| {
+            let shared_state = Arc::clone(&global_state_union);
             async move {
-                return handle_devname(shared_state, map, mount_device).await;
+                if let Some(err) = check_auth(&shared_state, auth) {
+                    return to_response(err.to_response());
+                }
+                to_response(set_union(branches, shared_state).await)
             }
         });
-    // Pretty much the same as the previous one, not going to repeat all the comments.
-    let umount = warp::path("umount")
+
+    // The "/status" route, for checking up on a single device. It only speaks JSON, so there's nothing to negotiate.
+    let status = warp::path("status")
         .and(warp::path::end())
         .and(warp::query::<FnvHashMap<String, String>>())
         .and_then(move |map: FnvHashMap<String, String>| {
-            let shared_state = Arc::clone(&global_state_clone);
+            let shared_state = Arc::clone(&global_state_status);
             async move {
-                return handle_devname(shared_state, map, umount_device).await;
+                handle_devname(
+                    shared_state,
+                    map,
+                    None,
+                    "status",
+                    DEVNAME_PARAMS,
+                    device_status,
+                )
+                .await
             }
-        });
+        })
+        .with(json_headers());
+    // The "/probe" route, for checking whether a device looks mountable without mounting it.
+    let probe = warp::path("probe")
+        .and(warp::path::end())
+        .and(warp::query::<FnvHashMap<String, String>>())
+        .and_then(move |map: FnvHashMap<String, String>| {
+            let shared_state = Arc::clone(&global_state_probe);
+            async move {
+                handle_devname(
+                    shared_state,
+                    map,
+                    None,
+                    "probe",
+                    DEVNAME_PARAMS,
+                    probe_device,
+                )
+                .await
+            }
+        })
+        .with(json_headers());
+    // The "/verify" route, for checking every layer of a mounted device, all the way up to the web root.
+    let verify = warp::path("verify")
+        .and(warp::path::end())
+        .and(warp::query::<FnvHashMap<String, String>>())
+        .and_then(move |map: FnvHashMap<String, String>| {
+            let shared_state = Arc::clone(&global_state_verify);
+            async move {
+                handle_devname(
+                    shared_state,
+                    map,
+                    None,
+                    "verify",
+                    DEVNAME_PARAMS,
+                    verify_device,
+                )
+                .await
+            }
+        })
+        .with(json_headers());
+    // The "/list" route, for listing the mounted devices by devname.
+    let list = warp::path("list")
+        .and(warp::path::end())
+        .and(warp::query::<FnvHashMap<String, String>>())
+        .and_then(move |map: FnvHashMap<String, String>| {
+            let shared_state = Arc::clone(&global_state_list);
+            async move { to_response(list_devices(&map, &shared_state)) }
+        })
+        .with(json_headers());
+    // The "/tree" route, for the whole picture in one go.
+    let tree = warp::path("tree")
+        .and(warp::path::end())
+        .then(move || {
+            let shared_state = Arc::clone(&global_state_tree);
+            async move { mount_tree(&shared_state).await }
+        })
+        .with(json_headers());
+    // The "/resolve" route, for finding out which branch a file is served from.
+    let resolve = warp::path("resolve")
+        .and(warp::path::end())
+        .and(warp::query::<FnvHashMap<String, String>>())
+        .and_then(move |map: FnvHashMap<String, String>| {
+            let shared_state = Arc::clone(&global_state_resolve);
+            async move { to_response(resolve_path(&map, &shared_state).await) }
+        })
+        .with(json_headers());
+    // The "/consistency" route, for checking that the live union is what we think it is.
+    let consistency = warp::path("consistency")
+        .and(warp::path::end())
+        .then(move || {
+            let shared_state = Arc::clone(&global_state_consistency);
+            async move { check_consistency(&shared_state).await }
+        })
+        .with(json_headers());
+    // The "/orphans" route, for spotting mounts we've lost track of.
+    let orphans = warp::path("orphans")
+        .and(warp::path::end())
+        .and_then(move || {
+            let shared_state = Arc::clone(&global_state_orphans);
+            async move { to_response(find_orphans(&shared_state).await) }
+        })
+        .with(json_headers());
+    // The "/history" route, for what's happened lately.
+    let history = warp::path("history")
+        .and(warp::path::end())
+        .map(move || versioned(json!({ "history": global_state_history.history.to_json() })))
+        .with(json_headers());
+    // The "/debug/inflight" route, for working out where a hung operation is stuck.
+    let inflight = warp::path!("debug" / "inflight")
+        .map(move || list_inflight(&global_state_inflight))
+        .with(json_headers());
+    // The "/debug/fault" route, for making the next mount of a device fail, or its mounts look dead. Test builds only.
+    #[cfg(any(test, feature = "test-hooks"))]
+    let inflight = {
+        let faults = warp::post()
+            .and(warp::path!("debug" / "fault"))
+            .and(warp::query::<FnvHashMap<String, String>>())
+            .and_then(move |map: FnvHashMap<String, String>| {
+                let shared_state = Arc::clone(&global_state_faults);
+                async move {
+                    handle_devname(shared_state, map, None, "fault", FAULT_PARAMS, inject_fault)
+                        .await
+                }
+            });
+        inflight.or(faults)
+    };
+    // The "/health" route, for how the mounts are holding up.
+    let health = warp::path("health")
+        .and(warp::path::end())
+        .map(move || {
+            let mounted = global_state_health.status.lock().mounted.len();
+            let (status, reason) = if !global_state_health.mount_capable {
+                ("degraded", Some("no_mount_capability"))
+            } else if !global_state_health.base_dir_present.load(Ordering::Relaxed) {
+                ("degraded", Some("base_dir_missing"))
+            } else if global_state_health.breaker.remaining().is_some() {
+                ("degraded", Some("circuit_open"))
+            } else {
+                ("ok", None)
+            };
+            versioned(json!({
+                "status": status,
+                "reason": reason,
+                "instance": global_state_health.config().instance_name,
+                "mounted": mounted,
+                "max_mounts": global_state_health.config().max_mounts,
+                // If the web server gets permission denied reading the union, this is the first place to look.
+                "fuse": {
+                    "user_allow_other": global_state_health.allow_other.user_allow_other,
+                    "allow_other_usable": global_state_health.allow_other.usable,
+                },
+                "dead_branches_evicted": global_state_health.metrics.dead_branches_evicted(),
+            }))
+        })
+        .with(json_headers());
+    // The "/ping" route, a liveness probe. It mustn't touch any locks or the filesystem, so that
+    // a daemon that's merely busy mounting still answers it immediately.
+    let ping = warp::path("ping").and(warp::path::end()).map(|| "pong");
+    // The admin "/config" route, for checking what the daemon actually made of its environment.
+    let config = warp::path("config")
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(move |auth: Option<String>| {
+            let shared_state = Arc::clone(&global_state_config);
+            async move {
+                if let Some(err) = check_auth(&shared_state, auth) {
+                    return to_response(err.to_response());
+                }
+                to_response(effective_config(&shared_state))
+            }
+        })
+        .with(json_headers());
+    // The "/version" route, for telling which daemon and which unionfs a host is running.
+    let version = warp::path("version")
+        .and(warp::path::end())
+        .map(move || {
+            versioned(json!({
+                "instance": global_state_version.config().instance_name,
+                "daemon": env!("CARGO_PKG_VERSION"),
+                "unionfs": global_state_version.unionfs_version,
+                "unionfs_remount": global_state_version.union_remount,
+            }))
+        })
+        .with(json_headers());
+    // The "/metrics" route, for Prometheus to scrape.
+    let metrics = warp::path("metrics").and(warp::path::end()).map(move || {
+        let mounted = global_state_metrics.status.lock().mounted.len();
+        global_state_metrics
+            .metrics
+            .render(mounted, &global_state_metrics.config().instance_name)
+    });
 
-    // Merge the routes into a single thing.
-    let routes = warp::get().and(mount).or(umount);
+    // Merge the routes into a single thing. Clients that aren't allowed in get a 403 before anything else happens.
+    // Oversized query strings are turned away before any of the routes get to parse one, and get a 413.
+    let routes = client_allowlist(global_state_clients)
+        .and(query_length_limit(max_query_bytes))
+        .and(
+            warp::get()
+                .and(mount)
+                .or(umount)
+                .or(union_set)
+                .or(replace)
+                .or(status)
+                .or(probe)
+                .or(verify)
+                .or(list)
+                .or(tree)
+                .or(resolve)
+                .or(consistency)
+                .or(orphans)
+                .or(history)
+                .or(inflight)
+                .or(ping)
+                .or(health)
+                .or(version)
+                .or(config)
+                .or(metrics),
+        )
+        .recover(handle_rejection)
+        // Count every request, including the ones that were turned away, by which endpoint it was for.
+        .with(warp::log::custom(move |info| {
+            global_state_requests.metrics.record_request(
+                endpoint_name(info.path()),
+                info.status().as_u16(),
+                info.elapsed(),
+            )
+        }));
 
     // Serve on port 3030. Let's hope this works.
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+    info!(
+        "Instance {} listening on 127.0.0.1:{}",
+        global_state_listen.config().instance_name,
+        PORT
+    );
+    server::serve(
+        warp::service(routes),
+        ([127, 0, 0, 1], PORT).into(),
+        global_state_listen,
+    )
+    .await;
 }
 
-/// Mounts a device, specified by the device's filename in `DEV_LOCATION`.
-async fn mount_device<T: BuildHasher>(
-    device_name: String,
-    shared_state: Arc<LockedMountStatus<T>>,
-) -> HTTPResponse {
-    // Construct some useful strings.
-    // The path to the device.
-    let devpath = DEV_LOCATION.to_owned() + &device_name;
-    // The fuse-archive mountpoint.
-    let zip_mountpt = "/tmp/".to_owned() + &device_name;
-    // The fuzzyfs mountpoint.
-    let fuzzy_mountpt = zip_mountpt.clone() + ".fuzzy";
-    // The location of the content folder inside the fuzzyfs mount.
-    // This will be used to construct the union mount. It's also used as a unique ID for this device.
-    let content = fuzzy_mountpt.clone() + "/content";
+/// Every endpoint we serve, by path, for labelling request metrics.
+const ENDPOINTS: &[&str] = &[
+    "/mount",
+    "/umount",
+    "/union/set",
+    "/replace",
+    "/status",
+    "/probe",
+    "/verify",
+    "/list",
+    "/tree",
+    "/resolve",
+    "/consistency",
+    "/orphans",
+    "/history",
+    "/debug/inflight",
+    "/ping",
+    "/health",
+    "/version",
+    "/config",
+    "/metrics",
+];
 
-    // Check: does the device exist?
-    match metadata(&devpath).await {
-        Ok(meta) => {
-            // Path exists, check that it's not a directory. Other than that, we're good to go.
-            if meta.is_dir() {
-                return HTTPResponse {
-                    status: 400,
-                    body: "Requested device is a directory : ".to_owned() + &device_name,
-                };
+/// The name of the endpoint a request went to, like `mount`. Anything else is `other`, so that a client requesting
+/// made-up paths can't create new series.
+fn endpoint_name(path: &str) -> &'static str {
+    ENDPOINTS
+        .iter()
+        .find(|endpoint| path == **endpoint)
+        .map_or("other", |endpoint| &endpoint[1..])
+}
+
+/// Re-reads the configuration from the environment and `path` whenever we get a SIGHUP, and switches to it. Settings that only take effect at
+/// startup keep the values we started with. A configuration that doesn't load or validate is ignored, and the
+/// old one stays in place.
+async fn reload_on_hangup<T: BuildHasher>(
+    shared_state: Arc<LockedMountStatus<T>>,
+    path: Option<String>,
+) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            warn!(
+                "Could not listen for SIGHUP, config reloading is off: {}",
+                err
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let mut config = match Config::load(path.as_deref()) {
+            Ok(config) => config,
+            Err(err) => {
+                error!("Not reloading the configuration: {}", err);
+                continue;
             }
+        };
+        if let Err(err) = config.validate() {
+            error!("Not reloading the configuration, it's invalid: {}", err);
+            continue;
         }
-        // Device doesn't exist.
-        Err(_) => {
-            return HTTPResponse {
-                status: 400,
-                body: "Requested device doesn't exist: ".to_owned() + &device_name,
-            };
+        let running = shared_state.config();
+        for (name, why) in config.keep_fixed(&running) {
+            warn!(
+                "{} can't change without a restart ({}), ignoring the new value",
+                name, why
+            );
+        }
+        // Say what changed, by comparing what /config would show.
+        let (old, new) = (running.to_json(), config.to_json());
+        let mut changed = 0;
+        if let (Some(old), Some(new)) = (old.as_object(), new.as_object()) {
+            for (name, value) in new {
+                let was = old.get(name).unwrap_or(&Value::Null);
+                if was != value {
+                    info!("{} changed from {} to {}", name, was, value);
+                    changed += 1;
+                }
+            }
         }
+        log::set_max_level(config.log_level);
+        *shared_state.config.write() = Arc::new(config);
+        info!("Reloaded the configuration, {} settings changed", changed);
     }
+}
 
-    // Verify that it's safe to proceed with mounting this device.
-    // We wouldn't want to attempt a mount if:
-    //  - The device is already mounted.
-    //  - The device is being mounted/unmounted by another request.
-    // So, we synchronize with some shared state.
-    {
-        let mut mount_status = shared_state.status.lock();
-        // Is it already mounted?
-        if mount_status.mounted.contains(&content) {
-            return HTTPResponse {
-                status: 200,
-                body: "Device is already mounted.".to_owned(),
-            };
+/// Waits until every path in `paths` exists, for at most `limit`. Logs whatever is still missing after that.
+async fn wait_for_paths(paths: &[&Path], limit: Duration) {
+    let deadline = Instant::now() + limit;
+    let mut logged = false;
+    loop {
+        let mut missing = Vec::new();
+        for path in paths {
+            if metadata(path).await.is_err() {
+                missing.push(path.display().to_string());
+            }
         }
-        // Is a mount operation currently in progess?
-        if mount_status.changing.contains(&content) {
-            return HTTPResponse {
-                status: 409,
-                body: "Mount operation already in progress.".to_owned(),
-            };
+        if missing.is_empty() {
+            return;
         }
-        // Checks passed, it's safe to proceed. Mark this device as in-progress.
-        mount_status.changing.insert(content.clone());
-    }
-
-    // Create the mountmounts in /tmp. For creating folders, we use create_dir_all.
-    // This is not because we expect /tmp to be missing, but because it won't throw an
-    // error if the target path already exists.
-    let dirs = join!(create_dir_all(&zip_mountpt), create_dir_all(&fuzzy_mountpt));
-    if dirs.0.is_err() || dirs.1.is_err() {
-        if let Some(err) = remove_changing(&content, &shared_state) {
-            return err;
+        if Instant::now() >= deadline {
+            error!(
+                "Still missing after waiting {:?}, serving anyway: {}",
+                limit,
+                missing.join(", ")
+            );
+            return;
         }
-        return HTTPResponse {
-            status: 500,
-            body: "Could not create mountpoints.".to_owned(),
-        };
+        if !logged {
+            info!("Waiting for {} to appear", missing.join(", "));
+            logged = true;
+        }
+        sleep(STARTUP_POLL_INTERVAL).await;
     }
+}
 
-    // Perform the fuse-archive mount.
-    // (sudo) fuse-archive /dev/sdb /tmp/sdb -o allow_other
-    let zipmount = Command::new(FUSE_ARCHIVE)
-        .arg(&devpath)
-        .arg(&zip_mountpt)
-        .arg("-o")
-        .arg("allow_other")
-        .spawn();
-    if let Some(err) = handle_subprocess(zipmount, &content, &shared_state).await {
-        return err;
-    }
+/// Checks that `BASE_DIR` is there, and is a directory.
+async fn is_base_dir_present() -> bool {
+    metadata(BASE_DIR).await.is_ok_and(|meta| meta.is_dir())
+}
 
-    // Perform the fuzzyfs mount.
-    // (sudo) fuzzyfs /tmp/sdb /tmp/sdb.fuzzy -o allow_other
-    let fuzzymount = Command::new(FUZZYFS)
-        .arg(&zip_mountpt)
-        .arg(&fuzzy_mountpt)
-        .arg("-o")
-        .arg("allow_other")
-        .spawn();
-    if let Some(err) = handle_subprocess(fuzzymount, &content, &shared_state).await {
-        // If we can't reliably spawn subprocesses, no point in trying to unmount the zip mount.
-        // This will be a code 500 anyway, that should be enough for people to get the idea that
-        // something went wrong.
-        return err;
-    }
+/// The directory that holds all of a device's mountpoints. Removing it is the last step of unmounting.
+fn device_dir(device_name: &str) -> String {
+    TMP_DIR.to_owned() + device_name
+}
 
-    // Check if the content folder exists.
-    let meta_res = metadata(&content).await;
-    let mut content_exists = true;
-    match meta_res {
-        Ok(meta) => {
-            if !meta.is_dir() {
-                // Something called "content" exists, but it's not what we're looking for.
-                content_exists = false;
+/// Derives the fuse-archive mountpoint, the fuzzyfs mountpoint, and the content folder for a device.
+/// Mount and unmount both go through here, so the two can't disagree about where things live.
+fn mountpoints(device_name: &str) -> (String, String, String) {
+    // Both mountpoints live in the device's own directory, so nothing one device mounts can be
+    // mistaken for another's.
+    let dir = device_dir(device_name);
+    // The fuse-archive mountpoint.
+    let zip_mountpt = dir.clone() + "/zip";
+    // The fuzzyfs mountpoint.
+    let fuzzy_mountpt = dir + "/fuzzy";
+    // The location of the content folder inside the fuzzyfs mount.
+    // This will be used to construct the union mount. It's also used as a unique ID for this device.
+    let content = fuzzy_mountpt.clone() + "/content";
+    (zip_mountpt, fuzzy_mountpt, content)
+}
+
+/// Handles a `/mount` request. Asynchronous ones (`async=`, or `FPVM_ASYNC` by default) are answered with a 202
+/// as soon as they've started, and their outcome goes to `/status` and the webhook. Synchronous ones wait for it.
+async fn mount_request<T, U>(
+    device_name: String,
+    params: HashMap<String, String, U>,
+    shared_state: Arc<LockedMountStatus<T>>,
+) -> HTTPResponse
+where
+    T: BuildHasher + Send + Sync + 'static,
+    U: BuildHasher + Send + 'static,
+{
+    match parse_bool_param(&params, "async", shared_state.config().async_default) {
+        Ok(true) => {
+            // Get the answers that don't take any work out of the way, so that only a mount that's
+            // really going to happen gets a 202.
+            let (_, _, content) = mountpoints(&device_name);
+            {
+                let mount_status = shared_state.status.lock();
+                if mount_status.mounted.contains_key(&content) {
+                    return MountError::AlreadyMounted.to_response();
+                }
+                if mount_status.changing.contains_key(&content) {
+                    return MountError::InProgress.to_response();
+                }
             }
+            if shared_state.config().breaker_threshold.is_some() {
+                if let Some(remaining) = shared_state.breaker.remaining() {
+                    return MountError::CircuitOpen(remaining).to_response();
+                }
+            }
+            tokio::spawn(mount_and_record(device_name, params, shared_state));
+            accepted()
         }
-        Err(_) => {
-            // The path doesn't exist.
-            content_exists = false;
-        }
-    }
-    // It doesn't exist. As part of clean-up, we unmount the things we mounted a moment ago.
-    if !content_exists {
-        if let Some(err) =
-            cleanup_mount(&shared_state, &zip_mountpt, &fuzzy_mountpt, &content).await
-        {
-            return err;
-        }
-        return HTTPResponse {
-            status: 500,
-            body: "No content folder.".to_owned(),
-        };
+        Ok(false) => mount_and_record(device_name, params, shared_state).await,
+        Err(err) => err.to_response(),
     }
+}
 
-    // The content folder exists! Now we mount it to the unionfs mount.
-    // shared_state.union is a mutex for controlling access to the unionfs mountpoint: /var/www/localhost/htdocs.
-    // We wouldn't want multiple things to be mounting/unmounting unionfs at the same time - that could cause race conditions.
-    // The lock also protects a number, because I couldn't figure out how to lock without data.
-    {
-        let mut count = shared_state.union.lock().await;
-        // Unmount the current unionfs.
-        // (sudo) umount -l /var/www/localhost/htdocs
-        let umount = Command::new(UMOUNT).arg("-l").arg(UNIONFS_MOUNTPT).spawn();
-        if let Some(err) = handle_subprocess(umount, &content, &shared_state).await {
-            return err;
-        }
-
-        // Grab the currently-mounted objects. Note that this is safe to unlock, because
-        // any modifiers of mount_status.mounted will also be holding the union lock.
-        // /root/base is always on top, and the current zip is directly after that.
-        // Beyond that, we guarantee nothing about ordering. Honestly, people should be
-        // using the umount api after a game closes anyway.
-        let mut mountlist: Vec<String> = vec![BASE_DIR.to_owned(), content.clone()];
-        {
-            let mount_status = shared_state.status.lock();
-            for key in &mount_status.mounted {
-                // PERF: zero-copy?
-                mountlist.push(key.clone());
+/// Handles an `/umount` request. Like `mount_request`, it's answered with a 202 straight away if it's asynchronous.
+async fn umount_request<T, U>(
+    device_name: String,
+    params: HashMap<String, String, U>,
+    shared_state: Arc<LockedMountStatus<T>>,
+) -> HTTPResponse
+where
+    T: BuildHasher + Send + Sync + 'static,
+    U: BuildHasher + Send + 'static,
+{
+    match parse_bool_param(&params, "async", shared_state.config().async_default) {
+        Ok(true) => {
+            if shared_state
+                .config()
+                .protected_devices
+                .contains(&device_name)
+            {
+                return MountError::Protected.to_response();
             }
+            let (_, _, content) = mountpoints(&device_name);
+            {
+                let mount_status = shared_state.status.lock();
+                if mount_status.changing.contains_key(&content) {
+                    return MountError::InProgress.to_response();
+                }
+                if !mount_status.mounted.contains_key(&content) {
+                    return MountError::NotMounted.to_response();
+                }
+            }
+            tokio::spawn(umount_and_record(device_name, params, shared_state));
+            accepted()
         }
+        Ok(false) => umount_and_record(device_name, params, shared_state).await,
+        Err(err) => err.to_response(),
+    }
+}
 
-        // Remount the unionfs mount.
-        // (sudo) unionfs /root/base:/tmp/sdb.fuzzy/content:/tmp/sda.fuzzy/content /var/www/localhost/htdocs -o allow_other
-        let mount = Command::new(UNIONFS)
-            .arg(mountlist.join(":"))
-            .arg(UNIONFS_MOUNTPT)
-            .arg("-o")
-            .arg("allow_other")
-            .spawn();
-        if let Some(err) = handle_subprocess(mount, &content, &shared_state).await {
-            return err;
-        }
-
-        // The zip is mounted! Move this device's status from changing (inflight) to mounted.
-        {
-            let mut mount_status = shared_state.status.lock();
-            mount_status.changing.remove(&content);
-            mount_status.mounted.insert(content);
+/// Mounts a device like `mount_device`, and keeps track of whether it failed. A new mount is a 201,
+/// and a device that was mounted already is a 200.
+async fn mount_and_record<T: BuildHasher + Send + Sync + 'static, U: BuildHasher>(
+    device_name: String,
+    params: HashMap<String, String, U>,
+    shared_state: Arc<LockedMountStatus<T>>,
+) -> HTTPResponse {
+    report_start(&shared_state, "mount", &device_name);
+    let started = Instant::now();
+    let response = match parse_bool_param(&params, "verbose", false) {
+        Ok(verbose) => {
+            let mount = mount_device(device_name.clone(), params, Arc::clone(&shared_state));
+            respond_verbose(verbose, mount, created).await
         }
-        // We have to use it so that it won't get dropped - the mutex unlocks on-drop.
-        *count += 1;
+        Err(err) => err.to_response(),
+    };
+    report_result(&shared_state, "mount", &device_name, &response, started);
+    if response.status < 400 {
+        shared_state.status.lock().failures.remove(&device_name);
+    } else if response.status != 409 {
+        // A 409 is about somebody else's operation, so it says nothing about this device. Anything else gets remembered.
+        remember_failure(&shared_state, device_name, &response);
     }
+    response
+}
 
-    // Yay, we made it!
-    HTTPResponse {
-        status: 201,
-        body: "OK".to_owned(),
+/// Remembers why a mount of `device_name` failed, for `/status` to report.
+fn remember_failure<T: BuildHasher>(
+    shared_state: &Arc<LockedMountStatus<T>>,
+    device_name: String,
+    response: &HTTPResponse,
+) {
+    let mut mount_status = shared_state.status.lock();
+    if mount_status.failures.len() >= MAX_FAILURES
+        && !mount_status.failures.contains_key(&device_name)
+    {
+        // Make room by forgetting an arbitrary device.
+        if let Some(evicted) = mount_status.failures.keys().next().cloned() {
+            mount_status.failures.remove(&evicted);
+        }
     }
+    mount_status.failures.insert(
+        device_name,
+        Failure {
+            code: response.code,
+            message: response.body.clone(),
+            timestamp: SystemTime::now(),
+        },
+    );
 }
 
-/// Unmounts a device, specified by the device's filename in `DEV_LOCATION`.
-async fn umount_device<T: BuildHasher>(
-    device_name: String,
+/// Mounts `new_name` in place of `old_name`, with a single union remount in between, so that there's no moment
+/// when neither is being served. The old device's fuse layers are torn down afterwards. Takes the same params as a
+/// mount, which apply to the new device.
+async fn replace_device<T: BuildHasher + Send + Sync + 'static, U: BuildHasher>(
+    old_name: String,
+    new_name: String,
+    params: HashMap<String, String, U>,
     shared_state: Arc<LockedMountStatus<T>>,
 ) -> HTTPResponse {
-    // Construct some useful strings.
-    // The fuse-archive mountpoint.
-    let zip_mountpt = "/tmp/".to_owned() + &device_name;
-    // The fuzzyfs mountpoint.
-    let fuzzy_mountpt = zip_mountpt.clone() + ".fuzzy";
-    // The location of the content folder inside the fuzzyfs mount.
-    // This will be used to construct the union mount. It's also used as a unique ID for this device.
-    let content = fuzzy_mountpt.clone() + "/content";
+    report_start(&shared_state, "replace", &new_name);
+    let started = Instant::now();
+    let response = replace_and_clean_up(&old_name, &new_name, params, &shared_state)
+        .await
+        .unwrap_or_else(|err| err.to_response());
+    report_result(&shared_state, "replace", &new_name, &response, started);
+    response
+}
 
-    // Verify that this device is indeed mounted. We wouldn't want to try unmounting a device that isn't mounted.
+async fn replace_and_clean_up<T: BuildHasher + Send + Sync + 'static, U: BuildHasher>(
+    old_name: &str,
+    new_name: &str,
+    params: HashMap<String, String, U>,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> Result<HTTPResponse, MountError> {
+    if shared_state
+        .config()
+        .protected_devices
+        .iter()
+        .any(|name| name == old_name)
+    {
+        return Err(MountError::Protected);
+    }
+    let (old_zip_mountpt, old_fuzzy_mountpt, old_content) = mountpoints(old_name);
+    if old_content == mountpoints(new_name).2 {
+        return Err(MountError::InvalidParam(
+            "old and new are the same device".to_owned(),
+        ));
+    }
+    // Claim the old device, the way an unmount would, but leave it in the union until the new one takes its place.
     {
         let mut mount_status = shared_state.status.lock();
-        if mount_status.changing.contains(&content) {
-            return HTTPResponse {
-                status: 409,
-                body: "Mount operation already in progress.".to_owned(),
-            };
-        }
-        if !mount_status.mounted.contains(&content) {
-            return HTTPResponse {
-                status: 200,
-                body: "Device is not mounted.".to_owned(),
-            };
+        if mount_status.changing.contains_key(&old_content) {
+            return Err(MountError::InProgress);
         }
-        // Set the status to changing *before* we do anything to avoid race conditions.
-        mount_status.mounted.remove(&content);
-        mount_status.changing.insert(content.clone());
+        let device = match mount_status.mounted.get(&old_content) {
+            Some(entry) => entry.device.clone(),
+            // Unlike for an unmount, that's not the outcome the client wanted, so it's not a 200.
+            None => {
+                return Err(MountError::InvalidParam(
+                    "Device to replace isn't mounted: ".to_owned() + old_name,
+                ))
+            }
+        };
+        let mut inflight = InFlight::new("replace", Phase::Preparing);
+        inflight.device = Some(device);
+        mount_status.changing.insert(old_content.clone(), inflight);
+    }
+    let _panic_guard = PanicGuard {
+        key: &old_content,
+        shared_state,
+    };
+
+    let mounted = mount_device_replacing(
+        new_name.to_owned(),
+        params,
+        Arc::clone(shared_state),
+        Some(&old_content),
+    )
+    .await;
+    if let Err(err) = mounted {
+        // The old device is still mounted and in the union, so all that's left to do is let go of it.
+        remove_changing(&old_content, shared_state);
+        return Err(err);
     }
 
-    // Okay, it's mounted. Time to unmount it.
+    // The new device is being served. Now the old one's fuse layers can go.
+    if let Some(err) = cleanup_mount(
+        shared_state,
+        &old_zip_mountpt,
+        &old_fuzzy_mountpt,
+        &old_content,
+    )
+    .await
     {
-        // Acquire the async union lock.
-        let mut count = shared_state.union.lock().await;
+        // The marker has to go even if cleanup failed, or the old device can never be mounted again.
+        remove_changing(&old_content, shared_state);
+        return Ok(HTTPResponse {
+            status: err.status(),
+            code: err.code(),
+            body: format!(
+                "{} replaced {}, but the old device couldn't be cleaned up: {}",
+                new_name,
+                old_name,
+                err.message()
+            ),
+        });
+    }
+    run_hook(
+        "post-unmount",
+        &shared_state.config().post_unmount_hook,
+        old_name,
+        shared_state,
+    )
+    .await;
+    Ok(HTTPResponse {
+        status: 200,
+        code: "ok",
+        body: format!("Replaced {} with {}", old_name, new_name),
+    })
+}
 
-        // Unmount the current unionfs.
-        // (sudo) umount -l /var/www/localhost/htdocs
-        let umount = Command::new(UMOUNT).arg("-l").arg(UNIONFS_MOUNTPT).spawn();
-        if let Some(err) = handle_subprocess(umount, &content, &shared_state).await {
-            return err;
+/// Unmounts a device like `umount_device`, and reports how it went. Success is a 200, never a 201.
+async fn umount_and_record<T: BuildHasher + Send + Sync + 'static, U: BuildHasher>(
+    device_name: String,
+    params: HashMap<String, String, U>,
+    shared_state: Arc<LockedMountStatus<T>>,
+) -> HTTPResponse {
+    report_start(&shared_state, "umount", &device_name);
+    let started = Instant::now();
+    let response = match parse_bool_param(&params, "verbose", false) {
+        Ok(verbose) => {
+            let umount = umount_device(device_name.clone(), params, Arc::clone(&shared_state));
+            respond_verbose(verbose, umount, unmounted).await
         }
+        Err(err) => err.to_response(),
+    };
+    report_result(&shared_state, "umount", &device_name, &response, started);
+    response
+}
 
-        // Change the status from mounted to changing, and pick up a list of mounted zips at the same time.
-        let mut mountlist: Vec<String> = vec![BASE_DIR.to_owned()];
-        {
-            let mount_status = shared_state.status.lock();
-            for key in &mount_status.mounted {
-                mountlist.push(key.clone());
+/// Runs a mount or unmount, and answers with `success` or the error. If `verbose`, everything the mount binaries
+/// printed is captured and added to the end of a successful answer, for debugging mounts that went through but
+/// don't behave.
+async fn respond_verbose(
+    verbose: bool,
+    op: impl Future<Output = Result<(), MountError>>,
+    success: fn() -> HTTPResponse,
+) -> HTTPResponse {
+    let (result, output) = if verbose {
+        capture_output(op).await
+    } else {
+        (op.await, Vec::new())
+    };
+    match result {
+        Ok(()) => {
+            let mut response = success();
+            if !output.is_empty() {
+                response.body += "\n\n";
+                response.body += &output.join("\n");
             }
+            response
         }
+        Err(err) => err.to_response(),
+    }
+}
 
-        // Remount the unionfs mount.
-        // (sudo) unionfs /root/base:/tmp/sda.fuzzy/content /var/www/localhost/htdocs -o allow_other
-        let mount = Command::new(UNIONFS)
-            .arg(mountlist.join(":"))
-            .arg(UNIONFS_MOUNTPT)
-            .arg("-o")
-            .arg("allow_other")
-            .spawn();
-        if let Some(err) = handle_subprocess(mount, &content, &shared_state).await {
-            return err;
-        }
+/// Tells event listeners that a mount or unmount is starting.
+fn report_start<T: BuildHasher>(
+    shared_state: &Arc<LockedMountStatus<T>>,
+    operation: &'static str,
+    device_name: &str,
+) {
+    shared_state.events.publish(json!({
+        "event": "started",
+        "operation": operation,
+        "devname": device_name,
+    }));
+}
 
-        // Modify it at the end so that the lock won't get dropped.
-        *count -= 1;
-    }
-    // We've successfully removed it from the union mount, continue to the other
-    // unmounting steps.
-    if let Some(err) = cleanup_mount(&shared_state, &zip_mountpt, &fuzzy_mountpt, &content).await {
-        return err;
+/// Adds the outcome of a mount or unmount to the history, sends it to the webhook, if there is one, and tells event
+/// listeners.
+fn report_result<T: BuildHasher>(
+    shared_state: &Arc<LockedMountStatus<T>>,
+    operation: &'static str,
+    device_name: &str,
+    response: &HTTPResponse,
+    started: Instant,
+) {
+    shared_state.history.record(
+        operation,
+        device_name,
+        response.status,
+        response.code,
+        started.elapsed().as_millis() as u64,
+    );
+    shared_state.events.publish(json!({
+        "event": if response.status < 400 { "succeeded" } else { "failed" },
+        "operation": operation,
+        "devname": device_name,
+        "status": response.status,
+        "code": response.code,
+        "duration_ms": started.elapsed().as_millis() as u64,
+    }));
+    let config = shared_state.config();
+    if let Some(url) = &config.webhook_url {
+        webhook::notify(
+            url,
+            json!({
+                "instance": config.instance_name,
+                "operation": operation,
+                "devname": device_name,
+                "success": response.status < 400,
+                "status": response.status,
+                "code": response.code,
+                "message": response.body,
+                "duration_ms": started.elapsed().as_millis() as u64,
+                "timestamp": format_timestamp(SystemTime::now()),
+            }),
+            config.retry_attempts,
+            config.retry_base_delay,
+        );
     }
+}
 
-    // Yay, we did it!
-    HTTPResponse {
+/// Reports the state of a device, and why its last mount failed, if it did.
+async fn device_status<T: BuildHasher, U: BuildHasher>(
+    device_name: String,
+    _params: HashMap<String, String, U>,
+    shared_state: Arc<LockedMountStatus<T>>,
+) -> HTTPResponse {
+    let (_, _, content) = mountpoints(&device_name);
+    let mount_status = shared_state.status.lock();
+    let last_failure = mount_status.failures.get(&device_name).map(|failure| {
+        json!({
+            "code": failure.code,
+            "message": failure.message,
+            "timestamp": format_timestamp(failure.timestamp),
+        })
+    });
+    let body = json!({
+        "devname": device_name,
+        "mounted": mount_status.mounted.contains_key(&content),
+        // Mounted, and done settling and warming, so it's safe to use.
+        "ready": mount_status.mounted.get(&content).is_some_and(|entry| entry.is_ready()),
+        // Answered, but not in the union until the next coalesced rebuild.
+        "pending_rebuild": mount_status.mounted.get(&content).is_some_and(|entry| entry.pending_rebuild),
+        "in_progress": mount_status.changing.contains_key(&content),
+        // What's in progress, if anything: `mount`, `umount` and so on.
+        "operation": mount_status.changing.get(&content).map(|inflight| inflight.operation),
+        // Where it is (or would be) mounted.
+        "paths": device_paths(&device_name),
+        "warm": mount_status
+            .mounted
+            .get(&content)
+            .and_then(|entry| entry.warm.as_ref())
+            .map(|warm| warm.to_json()),
+        "last_failure": last_failure,
+    });
+    HTTPResponse {
+        status: 200,
+        code: "ok",
+        body: versioned(body),
+    }
+}
+
+/// Reads a device's magic bytes and reports its format, and whether we could mount it. Doesn't touch any mount state.
+async fn probe_device<T: BuildHasher, U: BuildHasher>(
+    device_name: String,
+    _params: HashMap<String, String, U>,
+    shared_state: Arc<LockedMountStatus<T>>,
+) -> HTTPResponse {
+    let devpath = DEV_LOCATION.to_owned() + &device_name;
+    match stat_device(&devpath, &shared_state.config()).await {
+        Ok(meta) if meta.is_dir() => {
+            return directory_rejection(device_name, &devpath)
+                .await
+                .to_response();
+        }
+        Ok(_) => {}
+        Err(err) if is_not_found(&err) => {
+            return MountError::DeviceNotFound(device_name).to_response()
+        }
+        Err(err) => {
+            return MountError::DeviceStatFailed(device_name, err.to_string()).to_response()
+        }
+    }
+    let format = match detect_format(&devpath).await {
+        Ok(format) => format,
+        Err(_) => return MountError::DeviceReadFailed(device_name).to_response(),
+    };
+    let body = json!({
+        "devname": device_name,
+        "mountable": format.is_some_and(|format| format.mountable()),
+        "raw_mountable": format.is_some_and(|format| format.raw_mountable()),
+        "detected_format": format.map(|format| format.name()),
+    });
+    HTTPResponse {
+        status: 200,
+        code: "ok",
+        body: versioned(body),
+    }
+}
+
+/// Checks each layer of a mounted device in turn, and reports which of them look right. Every check is
+/// made, even after one fails, since which ones fail says where the problem is.
+async fn verify_device<T: BuildHasher, U: BuildHasher>(
+    device_name: String,
+    _params: HashMap<String, String, U>,
+    shared_state: Arc<LockedMountStatus<T>>,
+) -> HTTPResponse {
+    let (zip_mountpt, fuzzy_mountpt, content) = mountpoints(&device_name);
+    let (branches, in_union) = {
+        let mount_status = shared_state.status.lock();
+        match mount_status.mounted.get(&content) {
+            Some(entry) => (
+                entry.branches.clone(),
+                entry
+                    .branches
+                    .iter()
+                    .all(|branch| mount_status.branches.contains(branch)),
+            ),
+            // There's nothing to check the layers of.
+            None => {
+                return HTTPResponse {
+                    status: 200,
+                    code: "ok",
+                    body: versioned(
+                        json!({ "devname": device_name, "mounted": false, "ok": false }),
+                    ),
+                }
+            }
+        }
+    };
+    let archive_mounted = is_mountpoint(&zip_mountpt).await;
+    let fuzzyfs_mounted = is_live_fuse_mount(&fuzzy_mountpt).await;
+    // Every branch has to be there and have something in it. An empty one is as good as missing.
+    let mut content_present = true;
+    for branch in &branches {
+        content_present &= first_entry(branch).await.is_some();
+    }
+    let union_mounted = is_live_fuse_mount(UNIONFS_MOUNTPT).await;
+    // Something from the device, looked up through the web root.
+    let readable = match branches.first() {
+        Some(branch) => match first_entry(branch).await {
+            Some(name) => symlink_metadata(Path::new(UNIONFS_MOUNTPT).join(name))
+                .await
+                .is_ok(),
+            None => false,
+        },
+        None => false,
+    };
+    let body = json!({
+        "devname": device_name,
+        "mounted": true,
+        "ok": archive_mounted && fuzzyfs_mounted && content_present && in_union && union_mounted && readable,
+        "archive_mounted": archive_mounted,
+        "fuzzyfs_mounted": fuzzyfs_mounted,
+        "content_present": content_present,
+        "in_union": in_union,
+        "union_mounted": union_mounted,
+        "readable": readable,
+    });
+    HTTPResponse {
+        status: 200,
+        code: "ok",
+        body: versioned(body),
+    }
+}
+
+/// Lists the devnames of every mounted device, sorted, under `devices`. With `detail=true`, each one is an object
+/// that also has the device's paths, and whether it's ready to use.
+fn list_devices<T: BuildHasher, U: BuildHasher>(
+    params: &HashMap<String, String, U>,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> HTTPResponse {
+    let detail = match parse_bool_param(params, "detail", false) {
+        Ok(detail) => detail,
+        Err(err) => return err.to_response(),
+    };
+    let mount_status = shared_state.status.lock();
+    let mut entries: Vec<&MountEntry> = mount_status.mounted.values().collect();
+    entries.sort_unstable_by(|a, b| a.devname.cmp(&b.devname));
+    let body = if detail {
+        let devices: Vec<_> = entries
+            .into_iter()
+            .map(|entry| {
+                json!({
+                    "devname": entry.devname,
+                    "paths": device_paths(&entry.devname),
+                    "ready": entry.is_ready(),
+                })
+            })
+            .collect();
+        json!({ "devices": devices })
+    } else {
+        let devnames: Vec<&str> = entries.iter().map(|entry| entry.devname.as_str()).collect();
+        json!({ "devices": devnames })
+    };
+    HTTPResponse {
+        status: 200,
+        code: "ok",
+        body: versioned(body),
+    }
+}
+
+/// Where a device's mounts live, for clients that need to look at them directly rather than work them out.
+fn device_paths(device_name: &str) -> Value {
+    let (zip_mountpt, fuzzy_mountpt, content) = mountpoints(device_name);
+    json!({
+        "zip_mountpoint": zip_mountpt,
+        "fuzzy_mountpoint": fuzzy_mountpt,
+        "content": content,
+    })
+}
+
+/// Describes everything we're managing as one JSON document: the union's branch order, every
+/// device with its state, and whether its fuse mounts actually show up in the mount table.
+async fn mount_tree<T: BuildHasher>(shared_state: &Arc<LockedMountStatus<T>>) -> String {
+    // Read the mount table first, we can't hold the status lock across an await.
+    let mount_table: Option<FnvHashSet<String>> = read_mounts()
+        .await
+        .ok()
+        .map(|mounts| mounts.into_iter().map(|mount| mount.target).collect());
+    // `null` if we couldn't read the mount table, rather than claiming everything is missing.
+    let present = |path: &str| mount_table.as_ref().map(|table| table.contains(path));
+
+    let mount_status = shared_state.status.lock();
+    let mut union = vec![BASE_DIR.to_owned()];
+    union.extend(mount_status.branches.iter().cloned());
+
+    let mut devices: Vec<_> = mount_status.mounted.iter().collect();
+    devices.sort_unstable_by(|a, b| a.1.devname.cmp(&b.1.devname));
+    let devices: Vec<_> = devices
+        .into_iter()
+        .map(|(content, entry)| {
+            let (zip_mountpt, fuzzy_mountpt, _) = mountpoints(&entry.devname);
+            json!({
+                "devname": entry.devname,
+                "content_key": content,
+                "state": "mounted",
+                "mounted_at": format_timestamp(entry.mounted_at),
+                "raw": entry.raw,
+                "branches": entry.branches,
+                "fuse_mounts": [
+                    { "path": zip_mountpt, "present": present(&zip_mountpt) },
+                    { "path": fuzzy_mountpt, "present": present(&fuzzy_mountpt) },
+                ],
+            })
+        })
+        .collect();
+
+    // Operations in flight only have a content key so far.
+    let mut in_progress: Vec<&String> = mount_status.changing.keys().collect();
+    in_progress.sort_unstable();
+
+    versioned(json!({
+        "union": union,
+        "devices": devices,
+        "in_progress": in_progress,
+    }))
+}
+
+/// Reports which branch of the union `path` (relative to the web root) is served from, and which device that branch
+/// belongs to, if any. Also lists every lower branch that has the path too, and is shadowed by the winner.
+async fn resolve_path<T: BuildHasher, U: BuildHasher>(
+    params: &HashMap<String, String, U>,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> HTTPResponse {
+    let path = match params.get("path") {
+        Some(path) => path.trim_start_matches('/'),
+        None => {
+            return MountError::InvalidParam("Required GET param absent: 'path'".to_owned())
+                .to_response()
+        }
+    };
+    // Only ever look inside the branches.
+    if Path::new(path)
+        .components()
+        .any(|part| !matches!(part, Component::Normal(_)))
+    {
+        return MountError::InvalidParam("path must stay inside the web root".to_owned())
+            .to_response();
+    }
+    // Copy the branches and who they belong to out, so the lock isn't held across the stats.
+    let branches: Vec<(String, Option<String>)> = {
+        let mount_status = shared_state.status.lock();
+        std::iter::once(BASE_DIR.to_owned())
+            .chain(mount_status.branches.iter().cloned())
+            .map(|branch| {
+                let devname = mount_status
+                    .mounted
+                    .values()
+                    .find(|entry| entry.branches.contains(&branch))
+                    .map(|entry| entry.devname.clone());
+                (branch, devname)
+            })
+            .collect()
+    };
+    let mut found = Vec::new();
+    for (branch, devname) in branches {
+        if symlink_metadata(Path::new(&branch).join(path))
+            .await
+            .is_ok()
+        {
+            found.push(json!({ "branch": branch, "devname": devname }));
+        }
+    }
+    let mut found = found.into_iter();
+    let body = json!({
+        "path": path,
+        "served_from": found.next(),
+        "shadowed": found.collect::<Vec<_>>(),
+    });
+    HTTPResponse {
+        status: 200,
+        code: "ok",
+        body: versioned(body),
+    }
+}
+
+/// Checks that the live union has exactly the branches we think it should, in the same order. Reports both lists, the
+/// branches that are only in one of them, and whether the ones in both are in the same order.
+async fn check_consistency<T: BuildHasher>(shared_state: &Arc<LockedMountStatus<T>>) -> String {
+    let expected: Vec<String> = std::iter::once(BASE_DIR.to_owned())
+        .chain(shared_state.status.lock().branches.iter().cloned())
+        .collect();
+    let union_mounted = is_mountpoint(UNIONFS_MOUNTPT).await;
+    let actual = live_union_branches(UNIONFS_MOUNTPT, UNIONFS_SHADOW_MOUNTPT).await;
+    // No unionfs at all is as inconsistent as it gets: everything is missing.
+    let live = actual.clone().unwrap_or_default();
+    let missing: Vec<&String> = expected.iter().filter(|b| !live.contains(b)).collect();
+    let unexpected: Vec<&String> = live.iter().filter(|b| !expected.contains(b)).collect();
+    let in_both = |branches: &[String], other: &[String]| -> Vec<String> {
+        branches
+            .iter()
+            .filter(|b| other.contains(b))
+            .cloned()
+            .collect()
+    };
+    let order_matches = in_both(&expected, &live) == in_both(&live, &expected);
+    versioned(json!({
+        "consistent": union_mounted && actual.as_ref() == Some(&expected),
+        "union_mounted": union_mounted,
+        "expected": expected,
+        "actual": actual,
+        "missing": missing,
+        "unexpected": unexpected,
+        "order_matches": order_matches,
+    }))
+}
+
+/// Reports the effective configuration: everything read from the environment, plus the paths that are built in.
+fn effective_config<T: BuildHasher>(shared_state: &Arc<LockedMountStatus<T>>) -> HTTPResponse {
+    let body = json!({
+        "config": shared_state.config().to_json(),
+        "binaries": {
+            "fuse_archive": shared_state.config().fuse_archive,
+            "squashfuse": shared_state.config().squashfuse,
+            "fuzzyfs": FUZZYFS,
+            "mount": MOUNT,
+            "umount": UMOUNT,
+            "unionfs": UNIONFS,
+        },
+        "paths": {
+            "dev_location": DEV_LOCATION,
+            "base_dir": BASE_DIR,
+            "tmp_dir": TMP_DIR,
+            "union_mountpoint": UNIONFS_MOUNTPT,
+            "union_shadow_mountpoint": UNIONFS_SHADOW_MOUNTPT,
+        },
+        "docker": cfg!(feature = "docker"),
+    });
+    HTTPResponse {
+        status: 200,
+        code: "ok",
+        body: versioned(body),
+    }
+}
+
+/// Lists fuse mounts in our territory (the tmp dir and the union mountpoints) that we aren't tracking.
+/// These are usually left over from a crash. This only reports them, it doesn't clean anything up.
+async fn find_orphans<T: BuildHasher>(shared_state: &Arc<LockedMountStatus<T>>) -> HTTPResponse {
+    let mounts = match read_mounts().await {
+        Ok(mounts) => mounts,
+        Err(_) => return MountError::MountTableUnreadable.to_response(),
+    };
+
+    // Everything we know about: each mounted device's two fuse mounts, and the union.
+    let mut known: FnvHashSet<String> = FnvHashSet::default();
+    known.insert(UNIONFS_MOUNTPT.to_owned());
+    let mount_status = shared_state.status.lock();
+    for entry in mount_status.mounted.values() {
+        let (zip_mountpt, fuzzy_mountpt, _) = mountpoints(&entry.devname);
+        known.insert(zip_mountpt);
+        known.insert(fuzzy_mountpt);
+    }
+    // In-flight operations are only known by content key, and may be halfway through mounting.
+    // Anything in the same device directory as one of those belongs to it.
+    let in_flight = |target: &str| {
+        let dir = match Path::new(target).parent().and_then(Path::to_str) {
+            Some(dir) => dir.to_owned() + "/",
+            None => return false,
+        };
+        dir.len() > TMP_DIR.len()
+            && mount_status
+                .changing
+                .keys()
+                .any(|key| key.starts_with(&dir))
+    };
+
+    let orphans: Vec<_> = mounts
+        .iter()
+        .filter(|mount| mount.fstype.starts_with("fuse"))
+        .filter(|mount| {
+            mount.target.starts_with(TMP_DIR)
+                || mount.target == UNIONFS_MOUNTPT
+                || mount.target == UNIONFS_SHADOW_MOUNTPT
+        })
+        .filter(|mount| !known.contains(&mount.target) && !in_flight(&mount.target))
+        .map(|mount| json!({ "path": mount.target, "fstype": mount.fstype }))
+        .collect();
+    HTTPResponse {
+        status: 200,
+        code: "ok",
+        body: versioned(json!({ "orphans": orphans })),
+    }
+}
+
+/// Mounts a device, specified by the device's filename in `DEV_LOCATION`.
+async fn mount_device<T: BuildHasher + Send + Sync + 'static, U: BuildHasher>(
+    device_name: String,
+    params: HashMap<String, String, U>,
+    shared_state: Arc<LockedMountStatus<T>>,
+) -> Result<(), MountError> {
+    mount_device_replacing(device_name, params, shared_state, None).await
+}
+
+/// Mounts a device like `mount_device`. If `replacing` is the content key of a mounted device, which the caller
+/// must already have marked as changing, the new device's branches take its place in the same union remount, and
+/// it's dropped from `mounted`. Its fuse layers are left for the caller to tear down.
+async fn mount_device_replacing<T: BuildHasher + Send + Sync + 'static, U: BuildHasher>(
+    device_name: String,
+    params: HashMap<String, String, U>,
+    shared_state: Arc<LockedMountStatus<T>>,
+    replacing: Option<&str>,
+) -> Result<(), MountError> {
+    // With the breaker open, turn the mount away before it does any work.
+    let config = shared_state.config();
+    let threshold = match config.breaker_threshold {
+        Some(threshold) => threshold,
+        None => return mount_layers(device_name, params, shared_state, replacing).await,
+    };
+    if let Some(remaining) = shared_state.breaker.remaining() {
+        return Err(MountError::CircuitOpen(remaining));
+    }
+    let result = mount_layers(device_name, params, Arc::clone(&shared_state), replacing).await;
+    match &result {
+        Ok(()) => shared_state.breaker.succeeded(),
+        Err(err) if err.is_host_failure() => {
+            if shared_state.breaker.failed(
+                threshold,
+                config.breaker_window,
+                config.breaker_cooldown,
+            ) {
+                warn!(
+                    "Too many mounts have failed in a row, turning mounts away for {}ms",
+                    config.breaker_cooldown.as_millis()
+                );
+            }
+        }
+        Err(_) => {}
+    }
+    result
+}
+
+/// Does the work of `mount_device_replacing`, with no regard for the circuit breaker.
+async fn mount_layers<T: BuildHasher + Send + Sync + 'static, U: BuildHasher>(
+    device_name: String,
+    params: HashMap<String, String, U>,
+    shared_state: Arc<LockedMountStatus<T>>,
+    replacing: Option<&str>,
+) -> Result<(), MountError> {
+    let mut started = Instant::now();
+    // Where to download the archive from, if it isn't on a device. It's downloaded to the staging dir, and
+    // from then on mounted just like a device would be.
+    let url = params.get("url").cloned();
+    let staged_path = match (&url, &shared_state.config().staging_dir) {
+        (None, _) => None,
+        (Some(_), Some(staging_dir)) => {
+            Some(Path::new(staging_dir).join(device_name.replace('/', "_")))
+        }
+        (Some(_), None) => {
+            return Err(MountError::InvalidParam(
+                "Mounting from a url is disabled.".to_owned(),
+            ));
+        }
+    };
+    // Construct some useful strings.
+    // The path to the device, or to where its archive is downloaded to.
+    let devpath = match &staged_path {
+        Some(path) => path.display().to_string(),
+        None => DEV_LOCATION.to_owned() + &device_name,
+    };
+    // The fuse-archive mountpoint, the fuzzyfs mountpoint, and the content folder inside the fuzzyfs mount.
+    let (zip_mountpt, fuzzy_mountpt, content) = mountpoints(&device_name);
+
+    // How long to wait for the device to show up, if it isn't there yet.
+    let wait_ms = match params.get("wait_ms").map(|val| val.parse::<u64>()) {
+        None => 0,
+        Some(Ok(wait_ms)) => wait_ms.min(MAX_DEVICE_WAIT_MS),
+        Some(Err(_)) => {
+            return Err(MountError::InvalidParam(
+                "Couldn't parse wait_ms".to_owned(),
+            ));
+        }
+    };
+
+    // Which folders of the archive to serve. Just "content", unless the client says otherwise.
+    let subdirs = parse_subdirs(params.get("subdirs"))?;
+
+    // Whether the device is a plain filesystem image rather than an archive.
+    let raw = parse_bool_param(&params, "raw", shared_state.config().raw_mounts)?;
+
+    // Whether to insist on read-only fuse mounts. They should be anyway, but this makes sure.
+    let read_only = parse_bool_param(&params, "ro", shared_state.config().read_only)?;
+    let archive_options = fuse_options(&shared_state.config().fuse_archive_options, read_only);
+    let squashfuse_options = fuse_options(&shared_state.config().squashfuse_options, read_only);
+
+    // Whether to mount it with squashfuse rather than fuse-archive. `None` means to go by what's on the device.
+    let squashfs = match params.get("format").map(String::as_str) {
+        None | Some("auto") => None,
+        Some("archive") => Some(false),
+        Some("squashfs") => Some(true),
+        Some(_) => {
+            return Err(MountError::InvalidParam(
+                "format must be auto, archive or squashfs".to_owned(),
+            ));
+        }
+    };
+    let fuzzyfs_options = fuse_options(&shared_state.config().fuzzyfs_options, read_only);
+
+    // Whether to wait for the device's files to be readable through the union before answering.
+    let settle = parse_bool_param(&params, "settle", shared_state.config().settle)?;
+
+    // Whether somebody is waiting on this mount. It only matters if mounts have to queue.
+    let priority = match params.get("priority").map(String::as_str) {
+        None => Priority::Normal,
+        Some(value) => Priority::parse(value).ok_or_else(|| {
+            MountError::InvalidParam("priority must be high or normal".to_owned())
+        })?,
+    };
+
+    // Verify that it's safe to proceed with mounting this device.
+    // We wouldn't want to attempt a mount if:
+    //  - The device is already mounted.
+    //  - The device is being mounted/unmounted by another request.
+    // So, we synchronize with some shared state. This happens before anything else (even waiting for the
+    // device to show up), so that an unmount of the same device gets a 409 for as long as this request
+    // is running, rather than a "not mounted" that depends on how far along we are.
+    {
+        let mut mount_status = shared_state.status.lock();
+        // Is it already mounted?
+        if mount_status.mounted.contains_key(&content) {
+            return Err(MountError::AlreadyMounted);
+        }
+        // Is a mount operation currently in progess?
+        if mount_status.changing.contains_key(&content) {
+            return Err(MountError::InProgress);
+        }
+        // Would it be one too many? Mounts that haven't finished yet count too, or a burst could overshoot.
+        // A device that's replacing another doesn't add to the count.
+        if let Some(max) = shared_state.config().max_mounts {
+            let mounting = mount_status
+                .changing
+                .values()
+                .filter(|inflight| inflight.operation == "mount")
+                .count();
+            let mounted = mount_status.mounted.len() - usize::from(replacing.is_some());
+            if mounted + mounting >= max {
+                return Err(MountError::CapacityReached(max));
+            }
+        }
+        // Checks passed, it's safe to proceed. Mark this device as in-progress.
+        mount_status
+            .changing
+            .insert(content.clone(), InFlight::new("mount", Phase::Preparing));
+    }
+    let _panic_guard = PanicGuard {
+        key: &content,
+        shared_state: &shared_state,
+    };
+
+    // Wait for our turn, if there's a limit on how many mounts run at once. The device stays claimed meanwhile.
+    let _slot = match &shared_state.mount_slots {
+        Some(slots) => {
+            set_phase(&content, Phase::WaitingForSlot, &shared_state);
+            let slot = slots.acquire(priority).await;
+            // Time spent queueing doesn't count towards the mount timeout, or a burst would time out its own tail.
+            started = Instant::now();
+            Some(slot)
+        }
+        None => None,
+    };
+
+    // Fetch the archive, if it's coming from a url. Dropping this deletes it, so it gets moved into the
+    // device's entry once mounted, and is deleted along with it.
+    let mut staged = None;
+    if let (Some(url), Some(path)) = (&url, &staged_path) {
+        let download = download(
+            url,
+            path,
+            shared_state.config().max_download_bytes,
+            params.get("sha256").map(String::as_str),
+        );
+        // The download counts towards the mount timeout, or a stalled server could hold the device forever.
+        let downloaded = match shared_state.config().mount_timeout {
+            Some(limit) => timeout_at(started + limit, download)
+                .await
+                .unwrap_or(Err(MountError::Timeout)),
+            None => download.await,
+        };
+        match downloaded {
+            Ok(file) => staged = Some(file),
+            Err(err) => {
+                if let Some(err) = remove_changing(&content, &shared_state) {
+                    return Err(err);
+                }
+                return Err(err);
+            }
+        }
+    }
+
+    // Give the operator's hook a chance to get the device ready. It's only advisory: if it fails, the
+    // mount goes ahead anyway, and stands or falls on its own.
+    run_hook(
+        "pre-mount",
+        &shared_state.config().pre_mount_hook,
+        &device_name,
+        &shared_state,
+    )
+    .await;
+
+    // Check: does the device exist? The VM may still be attaching it, so poll until the deadline.
+    let deadline = Instant::now() + Duration::from_millis(wait_ms);
+    let meta_res = loop {
+        match stat_device(&devpath, &shared_state.config()).await {
+            Err(err) if is_not_found(&err) && Instant::now() < deadline => {
+                sleep(DEVICE_POLL_INTERVAL).await
+            }
+            res => break res,
+        }
+    };
+    match meta_res {
+        Ok(meta) => {
+            // Path exists, check that it's not a directory. Other than that, we're good to go.
+            if meta.is_dir() {
+                if let Some(err) = remove_changing(&content, &shared_state) {
+                    return Err(err);
+                }
+                return Err(directory_rejection(device_name, &devpath).await);
+            }
+        }
+        // Device doesn't exist.
+        Err(err) if is_not_found(&err) => {
+            if let Some(err) = remove_changing(&content, &shared_state) {
+                return Err(err);
+            }
+            return Err(MountError::DeviceNotFound(device_name));
+        }
+        // It might, but we couldn't tell. That's not the client's fault.
+        Err(err) => {
+            error!("Could not stat {}: {}", devpath, err);
+            if let Some(err) = remove_changing(&content, &shared_state) {
+                return Err(err);
+            }
+            return Err(MountError::DeviceStatFailed(device_name, err.to_string()));
+        }
+    }
+
+    // Check: is it a device we're allowed to mount? This goes by where the devname leads, not what it's called.
+    // Downloads are in our own staging dir, so there's nothing to check for them.
+    let resolved = canonicalize(&devpath)
+        .await
+        .map_or_else(|_| devpath.clone(), |path| path.display().to_string());
+    if staged.is_none() && !shared_state.config().device_allowed(&resolved) {
+        warn!(
+            "Refusing to mount {}: {} isn't allowed",
+            device_name, resolved
+        );
+        if let Some(err) = remove_changing(&content, &shared_state) {
+            return Err(err);
+        }
+        return Err(MountError::DeviceNotAllowed(resolved));
+    }
+
+    // Check: is the same device mounted, or being worked on, under another name? The checks on the content key
+    // only catch the same name twice. Two mounts of one device would fight over it, and unmounting either would
+    // pull it out from under the other.
+    {
+        let mut mount_status = shared_state.status.lock();
+        let in_use = mount_status
+            .mounted
+            .values()
+            .any(|entry| entry.device == resolved)
+            || mount_status.changing.iter().any(|(key, inflight)| {
+                key != &content && inflight.device.as_deref() == Some(resolved.as_str())
+            });
+        if !in_use {
+            if let Some(inflight) = mount_status.changing.get_mut(&content) {
+                inflight.device = Some(resolved.clone());
+            }
+        }
+        drop(mount_status);
+        if in_use {
+            if let Some(err) = remove_changing(&content, &shared_state) {
+                return Err(err);
+            }
+            return Err(MountError::DeviceInUse(resolved));
+        }
+    }
+
+    // Everything from here on counts towards the mount timeout. If it runs out, the mount is
+    // dropped wherever it's got to, and abort_mount cleans up after it.
+    let mount = async {
+        // The checks above only cover this process. If configured, also take an exclusive lock on the
+        // device node, so that another daemon on this host can't mount it at the same time. The lock is
+        // held for as long as the device stays mounted, and released when the file is dropped.
+        let device_lock = if shared_state.config().device_lock {
+            match lock_device(&devpath) {
+                Ok(file) => Some(file),
+                Err(err) => {
+                    if let Some(resp) = remove_changing(&content, &shared_state) {
+                        return Err(resp);
+                    }
+                    if err.kind() == ErrorKind::WouldBlock {
+                        return Err(MountError::LockedElsewhere);
+                    }
+                    return Err(MountError::DeviceLockFailed);
+                }
+            }
+        } else {
+            None
+        };
+
+        set_phase(&content, Phase::CreatingMountpoints, &shared_state);
+        // Create the mountmounts in /tmp. For creating folders, we use create_dir_all.
+        // This is not because we expect /tmp to be missing, but because it won't throw an
+        // error if the target path already exists.
+        let dirs = join!(create_dir_all(&zip_mountpt), create_dir_all(&fuzzy_mountpt));
+        let failed = match (&dirs.0, &dirs.1) {
+            (Ok(()), Ok(())) => None,
+            (Err(_), Ok(())) => Some("the fuse-archive mountpoint"),
+            (Ok(()), Err(_)) => Some("the fuzzyfs mountpoint"),
+            (Err(_), Err(_)) => Some("either mountpoint"),
+        };
+        if let Some(failed) = failed {
+            // Don't leave behind whichever one did get created, or the device's directory.
+            // Both are empty, so there's nothing to lose if these go wrong too.
+            if dirs.0.is_ok() {
+                let _ = remove_dir(&zip_mountpt).await;
+            }
+            if dirs.1.is_ok() {
+                let _ = remove_dir(&fuzzy_mountpt).await;
+            }
+            let _ = remove_dir(device_dir(&device_name)).await;
+            if let Some(err) = remove_changing(&content, &shared_state) {
+                return Err(err);
+            }
+            return Err(MountError::MountpointCreateFailed(failed));
+        }
+
+        // The device may have been hot-unplugged since we checked for it. Check again right before
+        // handing it to fuse-archive, whose own error for this case is anything but obvious.
+        if matches!(stat_device(&devpath, &shared_state.config()).await, Err(err) if is_not_found(&err))
+        {
+            if let Some(err) = remove_changing(&content, &shared_state) {
+                return Err(err);
+            }
+            return Err(MountError::DeviceDisappeared(device_name.clone()));
+        }
+
+        // fuse-archive can't read squashfs, so images that look like one go to squashfuse instead.
+        let squashfs = match squashfs {
+            Some(squashfs) => squashfs,
+            None => !raw && matches!(detect_format(&devpath).await, Ok(Some(Format::Squashfs))),
+        };
+
+        // Perform the fuse-archive mount.
+        set_phase(&content, Phase::MountingArchive, &shared_state);
+        #[cfg(any(test, feature = "test-hooks"))]
+        if let Some(err) = injected_fault(&device_name, Stage::Archive, &content, &shared_state) {
+            return Err(err);
+        }
+        let zipmount = if raw {
+            // The device is a filesystem already, so it gets a plain read-only mount instead. It unmounts the same way.
+            // (sudo) mount -o ro /dev/sdb /tmp/sdb/zip
+            privileged(&shared_state.config(), MOUNT)
+                .arg("-o")
+                .arg("ro")
+                .arg(&devpath)
+                .arg(&zip_mountpt)
+                .spawn()
+        } else if squashfs {
+            // (sudo) squashfuse /dev/sdb /tmp/sdb/zip -o allow_other
+            privileged(&shared_state.config(), &shared_state.config().squashfuse)
+                .arg(&devpath)
+                .arg(&zip_mountpt)
+                .args(&squashfuse_options)
+                .spawn()
+        } else {
+            // (sudo) fuse-archive /dev/sdb /tmp/sdb/zip -o allow_other
+            privileged(&shared_state.config(), &shared_state.config().fuse_archive)
+                .arg(&devpath)
+                .arg(&zip_mountpt)
+                .args(&archive_options)
+                .spawn()
+        };
+        if let Some(err) = handle_subprocess(zipmount, Some(&content), &shared_state).await {
+            // It can still vanish while fuse-archive is starting up. If that's why it failed, say so.
+            if matches!(stat_device(&devpath, &shared_state.config()).await, Err(err) if is_not_found(&err))
+            {
+                return Err(MountError::DeviceDisappeared(device_name.clone()));
+            }
+            return Err(err);
+        }
+
+        // Perform the fuzzyfs mount.
+        set_phase(&content, Phase::MountingFuzzyfs, &shared_state);
+        #[cfg(any(test, feature = "test-hooks"))]
+        if let Some(err) = injected_fault(&device_name, Stage::Fuzzyfs, &content, &shared_state) {
+            return Err(err);
+        }
+        // (sudo) fuzzyfs /tmp/sdb/zip /tmp/sdb/fuzzy -o allow_other
+        // Anything it complains about (ambiguous matches, say) goes to our log, since that's the
+        // only trace a "wrong file served" bug leaves.
+        let fuzzymount = privileged(&shared_state.config(), FUZZYFS)
+            .arg(&zip_mountpt)
+            .arg(&fuzzy_mountpt)
+            .args(&fuzzyfs_options)
+            .stderr(Stdio::piped())
+            .spawn()
+            .map(|mut child| {
+                log_stderr(&mut child, format!("fuzzyfs[{}]", device_name));
+                child
+            });
+        if let Some(err) = handle_subprocess(fuzzymount, Some(&content), &shared_state).await {
+            // If we can't reliably spawn subprocesses, no point in trying to unmount the zip mount.
+            // This will be a code 500 anyway, that should be enough for people to get the idea that
+            // something went wrong.
+            return Err(err);
+        }
+
+        // Make sure the ro option actually took, rather than trusting fuse to have passed it on.
+        if read_only {
+            for mountpt in [&zip_mountpt, &fuzzy_mountpt] {
+                if is_read_only(mountpt).await {
+                    continue;
+                }
+                error!("{} was mounted read-write despite ro", mountpt);
+                if let Some(err) =
+                    cleanup_mount(&shared_state, &zip_mountpt, &fuzzy_mountpt, &content).await
+                {
+                    return Err(err);
+                }
+                return Err(MountError::NotReadOnly(mountpt.clone()));
+            }
+        }
+
+        // Check that every requested folder exists. Each one becomes a union branch of its own.
+        set_phase(&content, Phase::CheckingContent, &shared_state);
+        let mut device_branches: Vec<String> = Vec::with_capacity(subdirs.len());
+        let content_deadline = Instant::now() + shared_state.config().content_wait;
+        for subdir in &subdirs {
+            let branch = fuzzy_mountpt.clone() + "/" + subdir;
+            let is_dir = wait_for_dir(&branch, &fuzzy_mountpt, content_deadline).await;
+            // Some archives keep their files at the root rather than in a content folder. If we're
+            // allowed to, serve the whole archive for those instead.
+            if !is_dir && subdir == DEFAULT_SUBDIR && shared_state.config().content_fallback_to_root
+            {
+                info!(
+                    "{} has no content folder, serving the archive root instead",
+                    device_name
+                );
+                device_branches.push(fuzzy_mountpt.clone());
+                continue;
+            }
+            // It doesn't exist (or isn't a folder). As part of clean-up, we unmount the things we mounted a moment ago.
+            if !is_dir {
+                if let Some(err) =
+                    cleanup_mount(&shared_state, &zip_mountpt, &fuzzy_mountpt, &content).await
+                {
+                    return Err(err);
+                }
+                return Err(MountError::NoContentFolder(subdir.clone()));
+            }
+            device_branches.push(branch);
+        }
+
+        // The content folder exists! Now we mount it to the unionfs mount.
+        // shared_state.union is a mutex for controlling access to the unionfs mountpoint: /var/www/localhost/htdocs.
+        // We wouldn't want multiple things to be mounting/unmounting unionfs at the same time - that could cause race conditions.
+        // The lock also protects a number, because I couldn't figure out how to lock without data.
+        // Something from the device to look for in the union once it's remounted, if we're settling.
+        let probe = match device_branches.first() {
+            Some(branch) if settle => first_entry(branch).await,
+            _ => None,
+        };
+
+        // With a quiet period, our branches join whatever else is waiting, and go in with a single rebuild that happens
+        // after we've answered. We don't take the union lock ourselves then: the rebuild takes it for all of us.
+        let quiet_period = shared_state
+            .config()
+            .union_quiet_period
+            .filter(|_| replacing.is_none());
+        set_phase(&content, Phase::AcquiringUnionLock, &shared_state);
+        {
+            let mut count = match quiet_period {
+                Some(_) => None,
+                None => Some(lock_union(&content, &shared_state).await),
+            };
+            set_phase(&content, Phase::RemountingUnion, &shared_state);
+            // Grab the currently-mounted objects. Note that this is safe to unlock, because
+            // anything adding to mount_status.branches will also be holding the union lock.
+            // /root/base is always on top, followed by the protected devices, and the current
+            // zip's folders are directly after those. The rest keep the order they had in the previous union.
+            let mut mountlist: Vec<String> = vec![BASE_DIR.to_owned()];
+            let insert_at;
+            // The branches of the device we're replacing, if any. Ours go where its first one was.
+            let replaced: Vec<String>;
+            {
+                let mount_status = shared_state.status.lock();
+                replaced = replacing
+                    .and_then(|key| mount_status.mounted.get(key))
+                    .map(|entry| entry.branches.clone())
+                    .unwrap_or_default();
+                let branches: Vec<&String> = mount_status
+                    .branches
+                    .iter()
+                    .filter(|branch| !replaced.contains(branch))
+                    .collect();
+                insert_at = match mount_status
+                    .branches
+                    .iter()
+                    .position(|branch| replaced.contains(branch))
+                {
+                    Some(pos) => pos,
+                    None => protected_branch_count(&mount_status, &shared_state.config()),
+                };
+                // PERF: zero-copy?
+                mountlist.extend(branches[..insert_at].iter().map(|branch| (*branch).clone()));
+                mountlist.extend(device_branches.iter().cloned());
+                mountlist.extend(branches[insert_at..].iter().map(|branch| (*branch).clone()));
+            }
+
+            // (sudo) unionfs /root/base:/tmp/sdb/fuzzy/content:/tmp/sda/fuzzy/content /var/www/localhost/htdocs -o allow_other
+            #[cfg(any(test, feature = "test-hooks"))]
+            if let Some(err) = injected_fault(&device_name, Stage::Union, &content, &shared_state) {
+                return Err(err);
+            }
+            if quiet_period.is_none() {
+                if let Some(err) = remount_union(&mountlist, Some(&content), &shared_state).await {
+                    return Err(err);
+                }
+            }
+
+            // The zip is mounted! Move this device's status from changing (inflight) to mounted.
+            {
+                let mut mount_status = shared_state.status.lock();
+                mount_status.changing.remove(&content);
+                if let Some(old) = replacing.and_then(|key| mount_status.mounted.remove(key)) {
+                    // Its branches are out of the union now. A warming task holding files open would keep fuzzyfs busy.
+                    if let Some(warm) = &old.warm {
+                        warm.cancel();
+                    }
+                    mount_status
+                        .branches
+                        .retain(|branch| !replaced.contains(branch));
+                }
+                // Something may have been unmounted since we looked, and taken branches before ours with it. With a
+                // quiet period, we're not holding the union lock either, so the list may have changed in other ways
+                // too. Ours go in for the rebuild to pick up.
+                let insert_at = insert_at.min(mount_status.branches.len());
+                mount_status
+                    .branches
+                    .splice(insert_at..insert_at, device_branches.iter().cloned());
+                if shared_state.config().overlap_check {
+                    let others = mountlist
+                        .iter()
+                        .filter(|branch| !device_branches.contains(branch))
+                        .cloned()
+                        .collect();
+                    tokio::spawn(overlap::report(
+                        device_name.clone(),
+                        device_branches.clone(),
+                        others,
+                    ));
+                }
+                // Prime fuse-archive's cache in the background, so the first real reads aren't the slow ones.
+                let warm = shared_state.config().warm_cache.then(|| {
+                    let progress = Arc::new(WarmProgress::default());
+                    tokio::spawn(warm::warm(
+                        device_name.clone(),
+                        device_branches.clone(),
+                        Arc::clone(&progress),
+                    ));
+                    progress
+                });
+                mount_status.mounted.insert(
+                    content.clone(),
+                    MountEntry {
+                        devname: device_name.clone(),
+                        device: resolved.clone(),
+                        branches: device_branches,
+                        mounted_at: SystemTime::now(),
+                        raw,
+                        _device_lock: device_lock,
+                        _staged: staged.take(),
+                        warm,
+                        visible: probe.is_none(),
+                        pending_rebuild: quiet_period.is_some(),
+                    },
+                );
+            }
+            // With a quiet period, we're done: the union is rebuilt with our branches in it once that's over.
+            if let Some(quiet_period) = quiet_period {
+                schedule_rebuild("mount", &device_name, &content, quiet_period, &shared_state);
+            }
+            // We have to use it so that it won't get dropped - the mutex unlocks on-drop.
+            if let Some(count) = &mut count {
+                **count += 1;
+            }
+        }
+
+        // Yay, we made it!
+        Ok(probe)
+    };
+    let probe = match shared_state.config().mount_timeout {
+        Some(limit) => match timeout_at(started + limit, mount).await {
+            Ok(result) => result,
+            Err(_) => Err(abort_mount(&device_name, &shared_state).await),
+        },
+        None => mount.await,
+    }?;
+    // This happens outside the mount timeout: the device is mounted by now, so a timeout has nothing to abort.
+    if let Some(probe) = probe {
+        let (_, _, content) = mountpoints(&device_name);
+        if let Err(err) = wait_visible(&probe, shared_state.config().settle_timeout).await {
+            // The client asked not to be answered until it could read the device. Failing it while leaving the
+            // device mounted would only get its retry an already_mounted, so it comes back out like any failed mount.
+            warn!(
+                "{} didn't show up in the union in time, unmounting it",
+                device_name
+            );
+            take_down(&content, "mount", &shared_state).await;
+            return Err(err);
+        }
+        if let Some(entry) = shared_state.status.lock().mounted.get_mut(&content) {
+            entry.visible = true;
+        }
+    }
+    Ok(())
+}
+
+/// The name of some entry in `branch`, for checking that the branch can be seen through the union.
+async fn first_entry(branch: &str) -> Option<String> {
+    let mut entries = read_dir(branch).await.ok()?;
+    let entry = entries.next_entry().await.ok()??;
+    entry.file_name().into_string().ok()
+}
+
+/// Polls for `dir` to show up as a directory in the fuse mount at `mountpt`, until `deadline`. A fuse mount can take
+/// a moment to appear after its binary returns, and until then it looks empty. Once anything else can be seen in
+/// it, the mount is up, and `dir` is missing for real.
+async fn wait_for_dir(dir: &str, mountpt: &str, deadline: Instant) -> bool {
+    loop {
+        if matches!(metadata(dir).await, Ok(meta) if meta.is_dir()) {
+            return true;
+        }
+        if Instant::now() >= deadline || first_entry(mountpt).await.is_some() {
+            return false;
+        }
+        sleep(DEVICE_POLL_INTERVAL).await;
+    }
+}
+
+/// Polls for `name` to show up in the union, until `limit` runs out.
+async fn wait_visible(name: &str, limit: Duration) -> Result<(), MountError> {
+    let path = Path::new(UNIONFS_MOUNTPT).join(name);
+    let deadline = Instant::now() + limit;
+    loop {
+        match symlink_metadata(&path).await {
+            Ok(_) => return Ok(()),
+            Err(_) if Instant::now() < deadline => sleep(DEVICE_POLL_INTERVAL).await,
+            Err(_) => return Err(MountError::NotVisible(path.display().to_string())),
+        }
+    }
+}
+
+/// Cleans up after a mount that ran out of time, whichever step it was on. Returns the error for the client.
+async fn abort_mount<T: BuildHasher>(
+    device_name: &str,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> MountError {
+    let (zip_mountpt, fuzzy_mountpt, content) = mountpoints(device_name);
+    let phase = shared_state
+        .status
+        .lock()
+        .changing
+        .get(&content)
+        .map(|inflight| inflight.phase);
+    warn!(
+        "Mount of {} timed out while {}, cleaning up",
+        device_name,
+        phase.map_or("preparing", |phase| phase.name())
+    );
+
+    // If it was cut off partway through remounting the union, the union may be down, or have the
+    // device in it. Put it back the way it was before this mount started.
+    if matches!(phase, Some(Phase::RemountingUnion)) {
+        // With a quiet period, its branches went into the list before the rebuild. They have to come back out.
+        {
+            let mut mount_status = shared_state.status.lock();
+            if !mount_status.mounted.contains_key(&content) {
+                let dir = device_dir(device_name) + "/";
+                mount_status
+                    .branches
+                    .retain(|branch| !branch.starts_with(&dir));
+            }
+        }
+        let _union = lock_union(&content, shared_state).await;
+        let mut mountlist: Vec<String> = vec![BASE_DIR.to_owned()];
+        mountlist.extend(shared_state.status.lock().branches.iter().cloned());
+        if let Some(err) = remount_union(&mountlist, Some(&content), shared_state).await {
+            error!(
+                "Could not restore the union after aborting {}: {}",
+                device_name,
+                err.message()
+            );
+        }
+    }
+
+    // Unmount whatever made it as far as being mounted, top to bottom.
+    for target in [&fuzzy_mountpt, &zip_mountpt] {
+        if !is_mountpoint(target).await {
+            continue;
+        }
+        // (sudo) umount /tmp/sdb/fuzzy
+        let unmount = privileged(&shared_state.config(), UMOUNT)
+            .arg(target)
+            .spawn();
+        if let Err(err) = wait_subprocess(unmount, shared_state.config().subprocess_timeout).await {
+            shared_state.metrics.record_subprocess_failure(err);
+            error!(
+                "Could not unmount {} after aborting: {}",
+                target,
+                err.code()
+            );
+        }
+    }
+    wait_unmounted(
+        &[&fuzzy_mountpt, &zip_mountpt],
+        shared_state.config().umount_settle,
+    )
+    .await;
+    // They may never have been created. Either way, there's nothing more to do about them.
+    let _ = join!(remove_dir(&fuzzy_mountpt), remove_dir(&zip_mountpt));
+    let _ = remove_dir(device_dir(device_name)).await;
+
+    shared_state.status.lock().changing.remove(&content);
+    MountError::Timeout
+}
+
+/// The error for a devname that turned out to be a directory. Says exactly what was found where, since with
+/// symlinks in `DEV_LOCATION` the devname alone doesn't.
+async fn directory_rejection(device_name: String, devpath: &str) -> MountError {
+    let file_type = match symlink_metadata(devpath).await {
+        Ok(meta) if meta.file_type().is_symlink() => "symlink to a directory",
+        _ => "directory",
+    };
+    let path = match canonicalize(devpath).await {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => devpath.to_owned(),
+    };
+    warn!(
+        "Rejected {}: {} resolved to {}, which is a {}",
+        device_name, devpath, path, file_type
+    );
+    MountError::DeviceIsDirectory {
+        devname: device_name,
+        path,
+        file_type,
+    }
+}
+
+/// The `-o` arguments for a fuse mount: the stage's configured options, plus `ro` if the mount has to be read-only.
+/// Empty if there are no options at all.
+fn fuse_options(options: &[String], read_only: bool) -> Vec<String> {
+    let mut options = options.to_vec();
+    if read_only && !options.iter().any(|option| option == "ro") {
+        options.push("ro".to_owned());
+    }
+    if options.is_empty() {
+        return Vec::new();
+    }
+    vec!["-o".to_owned(), options.join(",")]
+}
+
+/// Checks every mounted device's branches every `interval`, and evicts any whose fuse mount has died.
+async fn watch_branches<T: BuildHasher>(
+    shared_state: Arc<LockedMountStatus<T>>,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+        check_branches(&shared_state).await;
+    }
+}
+
+/// Checks every mounted device's branches once, and evicts any whose fuse mount has died.
+async fn check_branches<T: BuildHasher>(shared_state: &Arc<LockedMountStatus<T>>) {
+    // Copy the branches out, so the lock isn't held across the stats.
+    let mounted: Vec<(String, Vec<String>)> = {
+        let mount_status = shared_state.status.lock();
+        mount_status
+            .mounted
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.branches.clone()))
+            .collect()
+    };
+    for (content, branches) in mounted {
+        for branch in &branches {
+            if is_dead(branch, shared_state).await {
+                warn!("Branch {} is dead, evicting {}", branch, content);
+                evict_dead(&content, shared_state).await;
+                break;
+            }
+        }
+    }
+}
+
+/// Checks whether `path` is in a fuse mount whose process is gone, which answers everything with ENOTCONN. Tests can
+/// make a device's mounts look that way.
+async fn is_dead<T: BuildHasher>(path: &str, shared_state: &Arc<LockedMountStatus<T>>) -> bool {
+    injected_stale(path, shared_state) || is_stale(path).await
+}
+
+/// Takes a device whose fuse mount died out of the union, and cleans up what's left of its mounts.
+async fn evict_dead<T: BuildHasher>(content: &str, shared_state: &Arc<LockedMountStatus<T>>) {
+    if let Some(devname) = take_down(content, "evict", shared_state).await {
+        shared_state.metrics.record_dead_branch_eviction();
+        info!("Evicted {}", devname);
+    }
+}
+
+/// Takes a mounted device out of the union and unmounts it, without the checks and hooks of a client's unmount.
+/// It's marked as changing under `operation` meanwhile. Failures are logged, since there's nobody to report them to.
+/// Returns its devname, or `None` if it isn't mounted or something else is already working on it.
+async fn take_down<T: BuildHasher>(
+    content: &str,
+    operation: &'static str,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> Option<String> {
+    // Claim it like an unmount would. If someone else is already doing something with it, let them.
+    let devname = {
+        let mut mount_status = shared_state.status.lock();
+        if mount_status.changing.contains_key(content) {
+            return None;
+        }
+        let entry = mount_status.mounted.remove(content)?;
+        mount_status
+            .branches
+            .retain(|key| !entry.branches.contains(key));
+        if let Some(warm) = &entry.warm {
+            warm.cancel();
+        }
+        mount_status.changing.insert(
+            content.to_owned(),
+            InFlight::new(operation, Phase::AcquiringUnionLock),
+        );
+        entry.devname
+    };
+    let (zip_mountpt, fuzzy_mountpt, _) = mountpoints(&devname);
+    {
+        let _union = lock_union(content, shared_state).await;
+        set_phase(content, Phase::RemountingUnion, shared_state);
+        let mut mountlist: Vec<String> = vec![BASE_DIR.to_owned()];
+        mountlist.extend(shared_state.status.lock().branches.iter().cloned());
+        if let Some(err) = remount_union(&mountlist, Some(content), shared_state).await {
+            error!(
+                "Could not remount the union without {}: {}",
+                devname,
+                err.message()
+            );
+        }
+    }
+    if let Some(err) = cleanup_mount(shared_state, &zip_mountpt, &fuzzy_mountpt, content).await {
+        error!("Could not clean up after {}: {}", devname, err.message());
+    }
+    // The marker has to go even if cleanup failed, or the device can never be mounted again.
+    remove_changing(content, shared_state);
+    Some(devname)
+}
+
+/// How many branches at the front of `branches` belong to protected devices. Those always come
+/// first, in the order the devices were mounted, and everything else is mounted after them.
+fn protected_branch_count<T: BuildHasher>(mount_status: &MountStatus<T>, config: &Config) -> usize {
+    mount_status
+        .mounted
+        .values()
+        .filter(|entry| config.protected_devices.contains(&entry.devname))
+        .map(|entry| entry.branches.len())
+        .sum()
+}
+
+/// Mounts every protected device, in the configured order. Failures are logged, and don't stop the daemon from starting.
+async fn mount_protected<T: BuildHasher + Send + Sync + 'static>(
+    shared_state: &Arc<LockedMountStatus<T>>,
+) {
+    for device_name in &shared_state.config().protected_devices {
+        let response = mount_and_record(
+            device_name.clone(),
+            FnvHashMap::default(),
+            Arc::clone(shared_state),
+        )
+        .await;
+        if response.status < 400 {
+            info!("Mounted protected device {}", device_name);
+        } else {
+            error!(
+                "Could not mount protected device {}: {}",
+                device_name, response.body
+            );
+        }
+    }
+}
+
+/// The response for a mount that went through. The device is in the union now, where it wasn't before, hence the 201.
+/// A mount of a device that's already mounted changes nothing, so it gets a 200 instead (see `MountError::AlreadyMounted`).
+fn created() -> HTTPResponse {
+    HTTPResponse {
         status: 201,
+        code: "ok",
+        body: "OK".to_owned(),
+    }
+}
+
+/// The response for a mount or unmount that's been started in the background. Its outcome is on `/status`.
+fn accepted() -> HTTPResponse {
+    HTTPResponse {
+        status: 202,
+        code: "accepted",
+        body: "Accepted".to_owned(),
+    }
+}
+
+/// The response for an unmount that went through. Nothing was created, so it's a 200, just like an unmount of a
+/// device that wasn't mounted (see `MountError::NotMounted`). Clients can tell the two apart by the body.
+fn unmounted() -> HTTPResponse {
+    HTTPResponse {
+        status: 200,
+        code: "ok",
+        body: "OK".to_owned(),
+    }
+}
+
+/// Parses the comma-separated `subdirs` param. Each entry has to be a single plain folder name.
+fn parse_subdirs(param: Option<&String>) -> Result<Vec<String>, MountError> {
+    let param = match param {
+        Some(param) => param,
+        None => return Ok(vec![DEFAULT_SUBDIR.to_owned()]),
+    };
+    let mut subdirs: Vec<String> = Vec::new();
+    for subdir in param.split(',') {
+        // No nesting, no escaping the fuzzyfs mount, and no colons, which unionfs would take as a branch separator.
+        if subdir.is_empty() || subdir == "." || subdir == ".." || subdir.contains(['/', ':']) {
+            return Err(MountError::InvalidParam(
+                "Invalid subdir: ".to_owned() + subdir,
+            ));
+        }
+        if !subdirs.iter().any(|seen| seen == subdir) {
+            subdirs.push(subdir.to_owned());
+        }
+    }
+    Ok(subdirs)
+}
+
+/// Unmounts a device, specified by the device's filename in `DEV_LOCATION`. Everything it does is to our own
+/// mountpoints, so it works just the same if the device node has gone away, like after a hot-unplug.
+async fn umount_device<T: BuildHasher + Send + Sync + 'static, U: BuildHasher>(
+    device_name: String,
+    _params: HashMap<String, String, U>,
+    shared_state: Arc<LockedMountStatus<T>>,
+) -> Result<(), MountError> {
+    // Protected devices stay mounted for as long as the daemon runs.
+    if shared_state
+        .config()
+        .protected_devices
+        .contains(&device_name)
+    {
+        return Err(MountError::Protected);
+    }
+
+    // Construct some useful strings.
+    // The fuse-archive mountpoint, the fuzzyfs mountpoint, and the content folder inside the fuzzyfs mount.
+    let (zip_mountpt, fuzzy_mountpt, content) = mountpoints(&device_name);
+
+    // Verify that this device is indeed mounted. We wouldn't want to try unmounting a device that isn't mounted.
+    {
+        let mut mount_status = shared_state.status.lock();
+        if mount_status.changing.contains_key(&content) {
+            return Err(MountError::InProgress);
+        }
+        if !mount_status.mounted.contains_key(&content) {
+            return Err(MountError::NotMounted);
+        }
+        // Set the status to changing *before* we do anything to avoid race conditions.
+        let mut inflight = InFlight::new("umount", Phase::AcquiringUnionLock);
+        if let Some(entry) = mount_status.mounted.remove(&content) {
+            // The device stays claimed until it's unmounted, so it can't be mounted under another name meanwhile.
+            inflight.device = Some(entry.device.clone());
+            mount_status
+                .branches
+                .retain(|key| !entry.branches.contains(key));
+            // A warming task holding files open would keep fuzzyfs busy.
+            if let Some(warm) = &entry.warm {
+                warm.cancel();
+            }
+        }
+        mount_status.changing.insert(content.clone(), inflight);
+    }
+    let _panic_guard = PanicGuard {
+        key: &content,
+        shared_state: &shared_state,
+    };
+
+    // Wait for our turn, if there's a limit on how many unmounts run at once. The device stays claimed meanwhile.
+    let _slot = match &shared_state.umount_slots {
+        Some(slots) => {
+            set_phase(&content, Phase::WaitingForSlot, &shared_state);
+            Some(slots.acquire(Priority::Normal).await)
+        }
+        None => None,
+    };
+
+    // Okay, it's mounted. Time to unmount it. With a quiet period, our branches are already out of the list, and
+    // come out of the union along with those of anything else unmounted around now. That happens after we've
+    // answered, and so does the rest of the unmount, which the rebuild takes care of.
+    if let Some(quiet_period) = shared_state.config().union_quiet_period {
+        set_phase(&content, Phase::AwaitingRebuild, &shared_state);
+        schedule_rebuild(
+            "umount",
+            &device_name,
+            &content,
+            quiet_period,
+            &shared_state,
+        );
+        return Ok(());
+    }
+
+    // Acquire the async union lock.
+    let mut count = lock_union(&content, &shared_state).await;
+    set_phase(&content, Phase::RemountingUnion, &shared_state);
+
+    // Pick up the list of remaining branches.
+    let mut mountlist: Vec<String> = vec![BASE_DIR.to_owned()];
+    {
+        let mount_status = shared_state.status.lock();
+        for key in &mount_status.branches {
+            mountlist.push(key.clone());
+        }
+    }
+
+    // (sudo) unionfs /root/base:/tmp/sda/fuzzy/content /var/www/localhost/htdocs -o allow_other
+    if let Some(err) = remount_union(&mountlist, Some(&content), &shared_state).await {
+        return Err(err);
+    }
+
+    // Modify it at the end so that the lock won't get dropped.
+    *count -= 1;
+    drop(count);
+    // We've successfully removed it from the union mount, continue to the other
+    // unmounting steps.
+    finish_umount(
+        &device_name,
+        &zip_mountpt,
+        &fuzzy_mountpt,
+        &content,
+        &shared_state,
+    )
+    .await
+}
+
+/// The rest of an unmount once the device's branches are out of the union: unmounts its layers, and runs the
+/// post-unmount hook.
+async fn finish_umount<T: BuildHasher>(
+    device_name: &str,
+    zip_mountpt: &str,
+    fuzzy_mountpt: &str,
+    content: &str,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> Result<(), MountError> {
+    if let Some(err) = cleanup_mount(shared_state, zip_mountpt, fuzzy_mountpt, content).await {
+        return Err(err);
+    }
+    // Everything of ours is gone, so whatever the operator wants to do with the device now is safe.
+    run_hook(
+        "post-unmount",
+        &shared_state.config().post_unmount_hook,
+        device_name,
+        shared_state,
+    )
+    .await;
+
+    // Yay, we did it!
+    Ok(())
+}
+
+/// Runs one of the operator's hook commands with `device_name` as its last argument, and waits for it.
+/// A hook that fails is logged, but doesn't fail the operation. Does nothing if `hook` is empty.
+async fn run_hook<T: BuildHasher>(
+    which: &str,
+    hook: &[String],
+    device_name: &str,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) {
+    let (program, args) = match hook.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .arg(device_name)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped());
+    let child = command.spawn().map(|mut child| {
+        log_stderr(&mut child, format!("{} hook", which));
+        child
+    });
+    if let Err(err) = wait_subprocess(child, shared_state.config().subprocess_timeout).await {
+        shared_state.metrics.record_subprocess_failure(err);
+        warn!(
+            "{} hook for {} failed: {}",
+            which,
+            device_name,
+            err.to_response().body
+        );
+    }
+}
+
+/// Rebuilds the union from an explicit, ordered list of content keys. `BASE_DIR` stays on top, and the list has to
+/// pass `check_union_order`.
+async fn set_union<T: BuildHasher>(
+    branches: Vec<String>,
+    shared_state: Arc<LockedMountStatus<T>>,
+) -> HTTPResponse {
+    // Hold the union lock for the whole operation, so that the set of mounted devices can't
+    // change between validating the list and handing it to unionfs.
+    let _union = lock_union("union/set", &shared_state).await;
+
+    if let Err(err) = check_union_order(&branches, &shared_state) {
+        return err.to_response();
+    }
+
+    // Check that every branch is actually there before touching the live union.
+    for key in &branches {
+        match metadata(key).await {
+            Ok(meta) if meta.is_dir() => {}
+            _ => return MountError::BranchMissing(key.clone()).to_response(),
+        }
+    }
+
+    let mut mountlist: Vec<String> = vec![BASE_DIR.to_owned()];
+    mountlist.extend(branches.iter().cloned());
+    // Nothing is marked as changing here, so there's no failure key to clean up.
+    if let Some(err) = remount_union(&mountlist, None, &shared_state).await {
+        return err.to_response();
+    }
+    // An unmount may have started while we were busy. It will rebuild the union itself once we
+    // release the lock, but it mustn't find its key back in the branch list when it does.
+    {
+        let mut mount_status = shared_state.status.lock();
+        let still_mounted = branches
+            .into_iter()
+            .filter(|key| is_mounted_branch(&mount_status, key))
+            .collect();
+        mount_status.branches = still_mounted;
+    }
+
+    HTTPResponse {
+        status: 200,
+        code: "ok",
         body: "OK".to_owned(),
     }
 }
 
+/// Checks that `branches` is a new order for the union that mounts can live with. Every branch has to belong to a
+/// mounted device and appear once, and every mounted device's branches have to be there, or the device would stay
+/// mounted without being in the union. The protected devices' branches have to stay first, since that's where
+/// mounts expect to find them.
+fn check_union_order<T: BuildHasher>(
+    branches: &[String],
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> Result<(), MountError> {
+    let config = shared_state.config();
+    let mount_status = shared_state.status.lock();
+    let mut seen = FnvHashSet::default();
+    for key in branches {
+        if !is_mounted_branch(&mount_status, key) {
+            return Err(MountError::BranchNotMounted(key.clone()));
+        }
+        if !seen.insert(key.as_str()) {
+            return Err(MountError::DuplicateBranch(key.clone()));
+        }
+    }
+    for entry in mount_status.mounted.values() {
+        if let Some(key) = entry
+            .branches
+            .iter()
+            .find(|key| !seen.contains(key.as_str()))
+        {
+            return Err(MountError::BranchLeftOut(key.clone()));
+        }
+    }
+    let protected = protected_branch_count(&mount_status, &config);
+    match branches
+        .iter()
+        .skip(protected)
+        .find(|key| is_protected_branch(&mount_status, &config, key))
+    {
+        Some(key) => Err(MountError::ProtectedBranchMoved(key.clone())),
+        None => Ok(()),
+    }
+}
+
+/// Checks whether `key` is one of the union branches of a protected device.
+fn is_protected_branch<T: BuildHasher>(
+    mount_status: &MountStatus<T>,
+    config: &Config,
+    key: &str,
+) -> bool {
+    mount_status
+        .mounted
+        .values()
+        .filter(|entry| config.protected_devices.contains(&entry.devname))
+        .any(|entry| entry.branches.iter().any(|branch| branch == key))
+}
+
+/// Checks whether `key` is one of the union branches of a mounted device.
+fn is_mounted_branch<T: BuildHasher>(mount_status: &MountStatus<T>, key: &str) -> bool {
+    mount_status
+        .mounted
+        .values()
+        .any(|entry| entry.branches.iter().any(|branch| branch == key))
+}
+
+/// Tears down the unionfs mount and brings it back up with `mountlist` as its branches.
+/// The caller must be holding the union lock. On failure, `failure_key` is taken out of `changing`, if there is one.
+async fn remount_union<T: BuildHasher>(
+    mountlist: &[String],
+    failure_key: Option<&str>,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> Option<MountError> {
+    // Without base, unionfs would fail in a way that doesn't say why. Better not to take the union down for that.
+    let base_dir_present = is_base_dir_present().await;
+    shared_state
+        .base_dir_present
+        .store(base_dir_present, Ordering::Relaxed);
+    if !base_dir_present {
+        if let Some(err) = failure_key.and_then(|key| remove_changing(key, shared_state)) {
+            return Some(err);
+        }
+        return Some(MountError::BaseDirMissing);
+    }
+    // Changing the branches in place leaves no gap at all, so it beats even a swap. Anything that goes wrong with it
+    // falls back to a full rebuild.
+    if shared_state.union_remount && is_live_fuse_mount(UNIONFS_MOUNTPT).await {
+        if remount_branches(mountlist, shared_state).await {
+            shared_state.metrics.set_union_branches(mountlist.len());
+            return None;
+        }
+        warn!("Could not change the union's branches in place, rebuilding it");
+    }
+    if shared_state.config().atomic_union_swap || shared_state.config().union_double_buffer {
+        return swap_union(mountlist, failure_key, shared_state).await;
+    }
+
+    // Unmount the current unionfs.
+    // (sudo) umount -l /var/www/localhost/htdocs
+    let umount = privileged(&shared_state.config(), UMOUNT)
+        .arg("-l")
+        .arg(UNIONFS_MOUNTPT)
+        .spawn();
+    if let Some(err) = handle_subprocess(umount, failure_key, shared_state).await {
+        return Some(err);
+    }
+
+    // Remount the unionfs mount.
+    if let Some(err) = mount_union_at(mountlist, UNIONFS_MOUNTPT, failure_key, shared_state).await {
+        return Some(err);
+    }
+    shared_state.metrics.set_union_branches(mountlist.len());
+    None
+}
+
+/// Asks unionfs to give the live union `mountlist` as its branches, without unmounting it. Only for a unionfs that
+/// lists `remount` among its options; the one in the image doesn't, so it always gets a full rebuild. Returns
+/// whether it worked.
+async fn remount_branches<T: BuildHasher>(
+    mountlist: &[String],
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> bool {
+    let config = shared_state.config();
+    let mut options = vec!["remount".to_owned()];
+    options.extend(config.unionfs_options.iter().cloned());
+    // (sudo) unionfs -o remount,... /root/base:... /var/www/localhost/htdocs
+    let remount = privileged(&config, UNIONFS)
+        .arg(mountlist.join(":"))
+        .arg(UNIONFS_MOUNTPT)
+        .args(fuse_options(&options, config.read_only))
+        .spawn();
+    match wait_subprocess(remount, config.subprocess_timeout).await {
+        Ok(()) => is_live_fuse_mount(UNIONFS_MOUNTPT).await,
+        Err(err) => {
+            warn!("unionfs remount failed: {}", err.code());
+            false
+        }
+    }
+}
+
+/// The operations waiting for the next coalesced union rebuild.
+#[derive(Default)]
+struct Rebuilds {
+    /// Each answered mount and unmount whose change to the branch list the rebuild has to put into effect.
+    pending: Vec<PendingChange>,
+    /// Whether a rebuild is already scheduled, and will pick up anything added to `pending`.
+    scheduled: bool,
+}
+
+/// A mount or unmount that's been answered, but whose branches the union hasn't caught up with yet.
+struct PendingChange {
+    /// `mount` or `umount`.
+    operation: &'static str,
+    devname: String,
+    content: String,
+    /// When it was answered.
+    answered: Instant,
+}
+
+/// Queues a mount or unmount whose change is already in the branch list for the next coalesced rebuild, scheduling
+/// one for `quiet_period` from now if there isn't one already. Everything queued before the rebuild starts shares
+/// it. The caller answers its client straight away: a failed rebuild is reported in the history, the event stream
+/// and the webhook, and a mount's in `/status`.
+fn schedule_rebuild<T: BuildHasher + Send + Sync + 'static>(
+    operation: &'static str,
+    device_name: &str,
+    content: &str,
+    quiet_period: Duration,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) {
+    let schedule = {
+        let mut rebuilds = shared_state.rebuilds.lock();
+        rebuilds.pending.push(PendingChange {
+            operation,
+            devname: device_name.to_owned(),
+            content: content.to_owned(),
+            answered: Instant::now(),
+        });
+        !std::mem::replace(&mut rebuilds.scheduled, true)
+    };
+    if schedule {
+        tokio::spawn(coalesced_rebuild(quiet_period, Arc::clone(shared_state)));
+    }
+}
+
+/// Rebuilds the union once `quiet_period` is over, for everything queued by then, and then finishes off each of
+/// them: a mount is in the union, and an unmount can take its layers down.
+async fn coalesced_rebuild<T: BuildHasher>(
+    quiet_period: Duration,
+    shared_state: Arc<LockedMountStatus<T>>,
+) {
+    sleep(quiet_period).await;
+    let (pending, result) = {
+        let _union = lock_union("a coalesced rebuild", &shared_state).await;
+        // Everything queued by now has its change in the branch list, so this rebuild covers it.
+        let pending = {
+            let mut rebuilds = shared_state.rebuilds.lock();
+            rebuilds.scheduled = false;
+            std::mem::take(&mut rebuilds.pending)
+        };
+        let mut mountlist: Vec<String> = vec![BASE_DIR.to_owned()];
+        mountlist.extend(shared_state.status.lock().branches.iter().cloned());
+        (
+            pending,
+            remount_union(&mountlist, None, &shared_state).await,
+        )
+    };
+    info!("Rebuilt the union once for {} operations", pending.len());
+    for change in pending {
+        let result = match (&result, change.operation) {
+            (None, "mount") => {
+                // Unless it's been unmounted in the meantime, it's in the union now.
+                if let Some(entry) = shared_state.status.lock().mounted.get_mut(&change.content) {
+                    entry.pending_rebuild = false;
+                }
+                Ok(())
+            }
+            (None, _) => {
+                let (zip_mountpt, fuzzy_mountpt, _) = mountpoints(&change.devname);
+                finish_umount(
+                    &change.devname,
+                    &zip_mountpt,
+                    &fuzzy_mountpt,
+                    &change.content,
+                    &shared_state,
+                )
+                .await
+            }
+            (Some(err), "mount") => {
+                // Don't leave it looking mounted when it never made it into the union.
+                take_down(&change.content, "mount", &shared_state).await;
+                Err(err.clone())
+            }
+            (Some(err), _) => {
+                remove_changing(&change.content, &shared_state);
+                Err(err.clone())
+            }
+        };
+        if let Err(err) = result {
+            let response = err.to_response();
+            error!(
+                "{} of {} failed after it was answered: {}",
+                change.operation, change.devname, response.body
+            );
+            report_result(
+                &shared_state,
+                change.operation,
+                &change.devname,
+                &response,
+                change.answered,
+            );
+            if change.operation == "mount" {
+                remember_failure(&shared_state, change.devname, &response);
+            }
+        }
+    }
+}
+
+/// Runs unionfs to mount `mountlist` at `target`, and checks that the union really came up.
+/// unionfs can exit 0 without establishing the mount, so a missing union gets a few more tries, backing off
+/// between them, before we give up.
+async fn mount_union_at<T: BuildHasher>(
+    mountlist: &[String],
+    target: &str,
+    failure_key: Option<&str>,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> Option<MountError> {
+    let attempts = shared_state.config().union_mount_attempts.max(1);
+    // A subprocess failure is final, and comes back as Ok(Some). Only a union that didn't come up is retried.
+    let mounted = retry_with_backoff(
+        attempts,
+        shared_state.config().retry_base_delay,
+        |attempt| async move {
+            if attempt > 1 {
+                warn!(
+                    "Union at {} didn't come up, retrying ({}/{})",
+                    target, attempt, attempts
+                );
+                // Clear out whatever half-mounted thing is there. It's fine if there's nothing to unmount.
+                let _ = privileged(&shared_state.config(), UMOUNT)
+                    .arg("-l")
+                    .arg(target)
+                    .status()
+                    .await;
+            }
+            let read_only = shared_state.config().read_only;
+            let mount = privileged(&shared_state.config(), UNIONFS)
+                .arg(mountlist.join(":"))
+                .arg(target)
+                .args(fuse_options(
+                    &shared_state.config().unionfs_options,
+                    read_only,
+                ))
+                .spawn();
+            if let Some(err) = handle_subprocess(mount, failure_key, shared_state).await {
+                return Ok(Some(err));
+            }
+            if !is_live_fuse_mount(target).await {
+                return Err(());
+            }
+            // The union is shared by every device, so tearing it down over this would only make
+            // things worse. Make a lot of noise instead.
+            if read_only && !is_read_only(target).await {
+                error!("Union at {} was mounted read-write despite ro", target);
+            }
+            Ok(None)
+        },
+    )
+    .await;
+    if let Ok(result) = mounted {
+        return result;
+    }
+    error!(
+        "Union at {} didn't come up after {} attempts",
+        target, attempts
+    );
+    if let Some(err) = failure_key.and_then(|key| remove_changing(key, shared_state)) {
+        return Some(err);
+    }
+    Some(MountError::UnionNotMounted)
+}
+
+/// Like `remount_union`, but builds the new union on `UNIONFS_SHADOW_MOUNTPT` first and then moves it into place.
+/// The old union keeps serving while unionfs starts up, and stays up entirely if the new one fails to mount.
+async fn swap_union<T: BuildHasher>(
+    mountlist: &[String],
+    failure_key: Option<&str>,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> Option<MountError> {
+    if create_dir_all(UNIONFS_SHADOW_MOUNTPT).await.is_err() {
+        if let Some(err) = failure_key.and_then(|key| remove_changing(key, shared_state)) {
+            return Some(err);
+        }
+        return Some(MountError::MountpointCreateFailed(
+            "the shadow union mountpoint",
+        ));
+    }
+
+    // Mount the new union off to the side. If it doesn't come up, the live union is left alone.
+    // (sudo) unionfs /root/base:/tmp/sdb/fuzzy/content /tmp/union.next -o allow_other
+    if let Some(err) =
+        mount_union_at(mountlist, UNIONFS_SHADOW_MOUNTPT, failure_key, shared_state).await
+    {
+        return Some(err);
+    }
+
+    // Double buffered, we swap it in ourselves, with nothing in between the unmount and the move.
+    if shared_state.config().union_double_buffer {
+        return match replace_mount(UNIONFS_SHADOW_MOUNTPT, UNIONFS_MOUNTPT).await {
+            Ok(()) => {
+                shared_state.metrics.set_union_branches(mountlist.len());
+                None
+            }
+            Err(err) => {
+                error!("Could not swap in the new union: {}", err);
+                // The old union may or may not still be up, depending on which step failed. Either way, the new
+                // one mustn't be left in the way of the next swap.
+                let _ = privileged(&shared_state.config(), UMOUNT)
+                    .arg("-l")
+                    .arg(UNIONFS_SHADOW_MOUNTPT)
+                    .status()
+                    .await;
+                if let Some(err) = failure_key.and_then(|key| remove_changing(key, shared_state)) {
+                    return Some(err);
+                }
+                Some(MountError::UnionNotMounted)
+            }
+        };
+    }
+
+    // Swap it in. The only time nothing is mounted on htdocs is between these two commands.
+    // (sudo) umount -l /var/www/localhost/htdocs
+    let umount = privileged(&shared_state.config(), UMOUNT)
+        .arg("-l")
+        .arg(UNIONFS_MOUNTPT)
+        .spawn();
+    let mut result = handle_subprocess(umount, failure_key, shared_state).await;
+    if result.is_none() {
+        // (sudo) mount --move /tmp/union.next /var/www/localhost/htdocs
+        let move_mount = privileged(&shared_state.config(), MOUNT)
+            .arg("--move")
+            .arg(UNIONFS_SHADOW_MOUNTPT)
+            .arg(UNIONFS_MOUNTPT)
+            .spawn();
+        result = handle_subprocess(move_mount, failure_key, shared_state).await;
+    }
+    if result.is_some() {
+        // Don't leave the new union lying around, or the next swap won't be able to mount there.
+        // We're already reporting an error, so there's nothing to do if this fails too.
+        let _ = privileged(&shared_state.config(), UMOUNT)
+            .arg("-l")
+            .arg(UNIONFS_SHADOW_MOUNTPT)
+            .status()
+            .await;
+    } else {
+        shared_state.metrics.set_union_branches(mountlist.len());
+    }
+    result
+}
+
 /// Cleans up a non-unioned device mount. Except for synchronization errors, always removes the `union_mountpt` from `shared_state`.
 async fn cleanup_mount<T: BuildHasher>(
     shared_state: &Arc<LockedMountStatus<T>>,
     zip_mountpt: &str,
     fuzzy_mountpt: &str,
     union_mountpt: &str,
-) -> Option<HTTPResponse> {
+) -> Option<MountError> {
+    // Unmount both layers, top to bottom. A failure on the way doesn't stop us from trying the
+    // next one, or the fuse-archive mount would leak whenever fuzzyfs is busy.
+    let mut failures: Vec<(&str, SubprocessError)> = Vec::new();
+
     // Unmount the fuzzyfs mount.
-    // (sudo) umount /tmp/sdb.fuzzy
-    let fuzzy_unmount = Command::new(UMOUNT).arg(fuzzy_mountpt).spawn();
-    if let Some(err) = handle_subprocess(fuzzy_unmount, union_mountpt, shared_state).await {
-        return Some(err);
+    set_phase(union_mountpt, Phase::UnmountingFuzzyfs, shared_state);
+    // (sudo) umount /tmp/sdb/fuzzy
+    if let Some(err) = unmount_layer(shared_state, fuzzy_mountpt).await {
+        failures.push(("fuzzyfs", err));
     }
 
     // Unmount the fuse-archive mount.
-    let zip_unmount = Command::new(UMOUNT).arg(zip_mountpt).spawn();
-    if let Some(err) = handle_subprocess(zip_unmount, union_mountpt, shared_state).await {
-        return Some(err);
+    set_phase(union_mountpt, Phase::UnmountingArchive, shared_state);
+    // (sudo) umount /tmp/sdb/zip
+    if let Some(err) = unmount_layer(shared_state, zip_mountpt).await {
+        failures.push(("fuse-archive", err));
+    }
+
+    // Report every unmount that failed. The mountpoints can't be removed while anything's still mounted on them.
+    if !failures.is_empty() {
+        if let Some(err) = remove_changing(union_mountpt, shared_state) {
+            return Some(err);
+        }
+        return Some(MountError::UnmountFailed(failures));
     }
 
     // Delete the mount points.
-    let dirs = join!(remove_dir(fuzzy_mountpt), remove_dir(zip_mountpt));
-    if dirs.0.is_err() || dirs.1.is_err() {
+    set_phase(union_mountpt, Phase::RemovingMountpoints, shared_state);
+    // umount can return before the kernel has finished tearing a fuse mount down, and rmdir fails
+    // until it has. Give it a moment. If it takes longer than that, the rmdir will tell us.
+    let settle = shared_state.config().umount_settle;
+    if !wait_unmounted(&[fuzzy_mountpt, zip_mountpt], settle).await {
+        warn!(
+            "{} or {} still mounted after {:?}",
+            fuzzy_mountpt, zip_mountpt, settle
+        );
+    }
+    // Then the device's directory, which should be empty by now.
+    let dirs = join!(
+        remove_dir_if_present(fuzzy_mountpt),
+        remove_dir_if_present(zip_mountpt)
+    );
+    let parent = match Path::new(zip_mountpt).parent() {
+        Some(parent) if dirs.0.is_ok() && dirs.1.is_ok() => remove_dir_if_present(parent).await,
+        _ => Err(ErrorKind::Other.into()),
+    };
+    if parent.is_err() {
         if let Some(err) = remove_changing(union_mountpt, shared_state) {
             return Some(err);
         }
-        return Some(HTTPResponse {
-            status: 500,
-            body: "Could not remove mountpoints.".to_owned(),
-        });
+        // The unmounts themselves went through, which is what matters. Leftover dirs are only untidy.
+        if shared_state.config().ignore_rmdir_errors {
+            warn!(
+                "Could not remove {} and {}, leaving them",
+                fuzzy_mountpt, zip_mountpt
+            );
+            return None;
+        }
+        return Some(MountError::MountpointRemoveFailed);
     }
     // Remove the inflight marker for this device.
     remove_changing(union_mountpt, shared_state)
 }
 
+/// Removes an empty directory. One that's already gone counts as removed, so that cleaning up twice (after an
+/// earlier attempt that got partway, say) isn't an error. Anything else, like it not being empty, still is.
+async fn remove_dir_if_present(path: impl AsRef<Path>) -> std::io::Result<()> {
+    match remove_dir(path).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+/// Unmounts one of a device's fuse layers. If its process is gone, which is what happens to fuse-archive when the
+/// device is unplugged from under it, a plain umount can't even stat the mountpoint, so it's cleared the way
+/// `stale_unmount` says instead. That's tried too if a plain umount fails because the process died while it ran.
+/// Leaves `changing` alone, so that the device stays claimed until the caller has tried every layer.
+async fn unmount_layer<T: BuildHasher>(
+    shared_state: &Arc<LockedMountStatus<T>>,
+    mountpt: &str,
+) -> Option<SubprocessError> {
+    let config = shared_state.config();
+    if !is_dead(mountpt, shared_state).await {
+        // (sudo) umount /tmp/sdb/zip
+        let child = privileged(&config, UMOUNT).arg(mountpt).spawn();
+        match wait_subprocess(child, config.subprocess_timeout).await {
+            Ok(()) => return None,
+            Err(_) if is_dead(mountpt, shared_state).await => {}
+            Err(err) => {
+                shared_state.metrics.record_subprocess_failure(err);
+                return Some(err);
+            }
+        }
+    }
+    warn!("{} is stale, clearing it", mountpt);
+    let child = match config.stale_unmount {
+        // (sudo) umount -l /tmp/sdb/zip
+        StaleUnmount::Lazy => privileged(&config, UMOUNT).arg("-l").arg(mountpt).spawn(),
+        // (sudo) fusermount -uz /tmp/sdb/zip
+        StaleUnmount::Fusermount => privileged(&config, FUSERMOUNT)
+            .arg("-uz")
+            .arg(mountpt)
+            .spawn(),
+    };
+    let err = wait_subprocess(child, config.subprocess_timeout)
+        .await
+        .err()?;
+    shared_state.metrics.record_subprocess_failure(err);
+    Some(err)
+}
+
+/// Clears a device's `changing` marker if the operation holding it panics. Explicit error paths
+/// still call `remove_changing` themselves; this only covers what they can't.
+struct PanicGuard<'a, T: BuildHasher> {
+    key: &'a str,
+    shared_state: &'a Arc<LockedMountStatus<T>>,
+}
+
+impl<T: BuildHasher> Drop for PanicGuard<'_, T> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            error!("Operation on {} panicked, clearing its marker", self.key);
+            self.shared_state.status.lock().changing.remove(self.key);
+        }
+    }
+}
+
+/// Waits for the union lock, recording how long it took. `what` says who was waiting, for the log.
+/// How long it's held for is recorded when the returned guard is dropped.
+async fn lock_union<'a, T: BuildHasher>(
+    what: &str,
+    shared_state: &'a Arc<LockedMountStatus<T>>,
+) -> UnionGuard<'a, T> {
+    let started = Instant::now();
+    let guard = shared_state.union.lock().await;
+    let waited = started.elapsed();
+    shared_state.metrics.record_union_lock_wait(waited);
+    if waited > shared_state.config().union_wait_warn {
+        warn!("{} waited {:?} for the union lock", what, waited);
+    }
+    UnionGuard {
+        guard,
+        what: what.to_owned(),
+        acquired: Instant::now(),
+        shared_state,
+    }
+}
+
+/// The union lock, as held by `what` since `acquired`.
+struct UnionGuard<'a, T: BuildHasher> {
+    guard: tokio::sync::MutexGuard<'a, i32>,
+    what: String,
+    acquired: Instant,
+    shared_state: &'a Arc<LockedMountStatus<T>>,
+}
+
+impl<T: BuildHasher> Deref for UnionGuard<'_, T> {
+    type Target = i32;
+
+    fn deref(&self) -> &i32 {
+        &self.guard
+    }
+}
+
+impl<T: BuildHasher> DerefMut for UnionGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut i32 {
+        &mut self.guard
+    }
+}
+
+impl<T: BuildHasher> Drop for UnionGuard<'_, T> {
+    fn drop(&mut self) {
+        let held = self.acquired.elapsed();
+        self.shared_state.metrics.record_union_lock_hold(held);
+        if held > self.shared_state.config().union_hold_warn {
+            warn!("{} held the union lock for {:?}", self.what, held);
+        }
+    }
+}
+
+/// Records that the in-flight operation on `key` has moved on to `phase`.
+fn set_phase<T: BuildHasher>(key: &str, phase: Phase, shared_state: &Arc<LockedMountStatus<T>>) {
+    let mut mount_status = shared_state.status.lock();
+    if let Some(inflight) = mount_status.changing.get_mut(key) {
+        inflight.enter(phase);
+        shared_state.events.publish(json!({
+            "event": "phase",
+            "operation": inflight.operation,
+            "content_key": key,
+            "phase": phase.name(),
+        }));
+    }
+}
+
+/// Lists every in-flight operation, with the phase it's in and how long it's been there.
+fn list_inflight<T: BuildHasher>(shared_state: &Arc<LockedMountStatus<T>>) -> String {
+    let mount_status = shared_state.status.lock();
+    let mut ops: Vec<_> = mount_status.changing.iter().collect();
+    ops.sort_unstable_by_key(|(_, inflight)| inflight.started);
+    let ops: Vec<_> = ops
+        .into_iter()
+        .map(|(key, inflight)| {
+            json!({
+                "content_key": key,
+                "operation": inflight.operation,
+                "phase": inflight.phase.name(),
+                "phase_secs": inflight.phase_started.elapsed().as_secs_f64(),
+                "total_secs": inflight.started.elapsed().as_secs_f64(),
+            })
+        })
+        .collect();
+    versioned(json!({ "operations": ops }))
+}
+
 /// Removes a key from the shared state's `changing` hashset. Returns an error, or `None`.
 fn remove_changing<T: BuildHasher>(
     key: &str,
     shared_state: &Arc<LockedMountStatus<T>>,
-) -> Option<HTTPResponse> {
+) -> Option<MountError> {
     let mut mount_status = shared_state.status.lock();
     mount_status.changing.remove(key);
     None
 }
 
-/// Wait for a process to spawn and exit, and handle any errors that result.
-async fn handle_subprocess<T: BuildHasher>(
-    spawnedproc: std::io::Result<Child>,
+/// Fails a mount at `stage` the way a failed subprocess would, if a test set that up for `device_name`.
+/// Whatever the real failure would leave behind is left behind too.
+#[cfg(any(test, feature = "test-hooks"))]
+fn injected_fault<T: BuildHasher>(
+    device_name: &str,
+    stage: Stage,
     failure_key: &str,
     shared_state: &Arc<LockedMountStatus<T>>,
-) -> Option<HTTPResponse> {
-    match spawnedproc {
-        // Did it spawn successfully?
-        Ok(mut child) => {
-            // Yup, wait for it to complete.
-            match child.wait().await {
-                Ok(status_code) => {
-                    // Check that it was successful.
-                    if !status_code.success() {
-                        if let Some(err) = remove_changing(failure_key, shared_state) {
-                            return Some(err);
-                        }
-                        return Some(HTTPResponse {
-                            status: 500,
-                            body: "Subprocess exited with an unsuccessful status.".to_owned(),
-                        });
-                    }
-                    None
-                }
-                Err(_) => {
-                    if let Some(err) = remove_changing(failure_key, shared_state) {
-                        return Some(err);
-                    }
-                    Some(HTTPResponse {
-                        status: 500,
-                        body: "Could not read subprocess status.".to_owned(),
-                    })
-                }
-            }
-        }
-        Err(_) => {
-            if let Some(err) = remove_changing(failure_key, shared_state) {
-                return Some(err);
+) -> Option<MountError> {
+    let err = shared_state.faults.take(device_name, stage)?;
+    warn!("Failing {} with an injected {}", device_name, err.code());
+    shared_state.metrics.record_subprocess_failure(err);
+    if let Some(resp) = remove_changing(failure_key, shared_state) {
+        return Some(resp);
+    }
+    Some(MountError::SubprocessFailed(err))
+}
+
+/// Whether a test has made `path` look like it's in a dead fuse mount.
+#[cfg(any(test, feature = "test-hooks"))]
+fn injected_stale<T: BuildHasher>(path: &str, shared_state: &Arc<LockedMountStatus<T>>) -> bool {
+    shared_state.faults.is_stale(path)
+}
+
+/// Without test hooks, nothing is dead unless it really is.
+#[cfg(not(any(test, feature = "test-hooks")))]
+fn injected_stale<T: BuildHasher>(_path: &str, _shared_state: &Arc<LockedMountStatus<T>>) -> bool {
+    false
+}
+
+/// Sets up a fault for the next mount of a devname. Takes `stage` (`fuse-archive`, `fuzzyfs` or `union`) and
+/// `code` (`spawn_failed`, `wait_failed`, `nonzero_exit` or `timed_out`). `stage=stale` instead makes the device's
+/// mounts look dead to the branch check from now on, and takes no `code`.
+#[cfg(any(test, feature = "test-hooks"))]
+async fn inject_fault<T: BuildHasher, U: BuildHasher>(
+    device_name: String,
+    params: HashMap<String, String, U>,
+    shared_state: Arc<LockedMountStatus<T>>,
+) -> HTTPResponse {
+    if params.get("stage").map(String::as_str) == Some("stale") {
+        shared_state.faults.mark_stale(&device_name);
+        return HTTPResponse {
+            status: 200,
+            code: "ok",
+            body: "OK".to_owned(),
+        };
+    }
+    let stage = params.get("stage").and_then(|stage| Stage::parse(stage));
+    let code = params.get("code").and_then(|code| faults::parse_code(code));
+    match (stage, code) {
+        (Some(stage), Some(err)) => {
+            shared_state.faults.inject(device_name, stage, err);
+            HTTPResponse {
+                status: 200,
+                code: "ok",
+                body: "OK".to_owned(),
             }
-            Some(HTTPResponse {
-                status: 500,
-                body: "Could not spawn subprocess.".to_owned(),
-            })
         }
+        (None, _) => MountError::InvalidParam(
+            "stage must be fuse-archive, fuzzyfs, union or stale".to_owned(),
+        )
+        .to_response(),
+        (_, None) => MountError::InvalidParam(
+            "code must be spawn_failed, wait_failed, nonzero_exit or timed_out".to_owned(),
+        )
+        .to_response(),
+    }
+}
+
+/// Wait for a process to spawn and exit, and handle any errors that result. On failure, `failure_key` is taken out
+/// of `changing`, if there is one.
+async fn handle_subprocess<T: BuildHasher>(
+    spawnedproc: std::io::Result<Child>,
+    failure_key: Option<&str>,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> Option<MountError> {
+    let err = wait_subprocess(spawnedproc, shared_state.config().subprocess_timeout)
+        .await
+        .err()?;
+    subprocess_failed(err, failure_key, shared_state)
+}
+
+/// Deals with a failed subprocess the way `handle_subprocess` does, for callers that waited for it themselves.
+fn subprocess_failed<T: BuildHasher>(
+    err: SubprocessError,
+    failure_key: Option<&str>,
+    shared_state: &Arc<LockedMountStatus<T>>,
+) -> Option<MountError> {
+    shared_state.metrics.record_subprocess_failure(err);
+    if let Some(resp) = failure_key.and_then(|key| remove_changing(key, shared_state)) {
+        return Some(resp);
     }
+    Some(MountError::SubprocessFailed(err))
 }
@@ -0,0 +1,229 @@
+use crate::subprocess::SubprocessError;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters and gauges exposed on `/metrics`, in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    spawn_failed: AtomicU64,
+    wait_failed: AtomicU64,
+    nonzero_exit: AtomicU64,
+    timed_out: AtomicU64,
+    union_branches: AtomicU64,
+    union_lock_wait: Histogram,
+    union_lock_hold: Histogram,
+    dead_branches_evicted: AtomicU64,
+    /// How long HTTP requests took, by endpoint and status. The counts double as request counters.
+    requests: Mutex<BTreeMap<(&'static str, u16), Histogram>>,
+}
+
+impl Metrics {
+    /// Counts a failed subprocess, by the kind of failure.
+    pub fn record_subprocess_failure(&self, err: SubprocessError) {
+        let counter = match err {
+            SubprocessError::SpawnFailed => &self.spawn_failed,
+            SubprocessError::WaitFailed => &self.wait_failed,
+            SubprocessError::NonZeroExit { .. } => &self.nonzero_exit,
+            SubprocessError::TimedOut => &self.timed_out,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records how many branches (including base) were handed to unionfs by the last successful remount.
+    pub fn set_union_branches(&self, branches: usize) {
+        self.union_branches
+            .store(branches as u64, Ordering::Relaxed);
+    }
+
+    /// Records how long one request waited to get the union lock.
+    pub fn record_union_lock_wait(&self, waited: Duration) {
+        self.union_lock_wait.observe(waited);
+    }
+
+    /// Records how long one request held the union lock.
+    pub fn record_union_lock_hold(&self, held: Duration) {
+        self.union_lock_hold.observe(held);
+    }
+
+    /// Counts a device that was taken out of the union because its fuse mount died.
+    pub fn record_dead_branch_eviction(&self) {
+        self.dead_branches_evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records an HTTP request to `endpoint`, which was answered with `status` after `elapsed`.
+    pub fn record_request(&self, endpoint: &'static str, status: u16, elapsed: Duration) {
+        self.requests
+            .lock()
+            .entry((endpoint, status))
+            .or_default()
+            .observe(elapsed);
+    }
+
+    /// How many devices have been evicted for having dead branches.
+    pub fn dead_branches_evicted(&self) -> u64 {
+        self.dead_branches_evicted.load(Ordering::Relaxed)
+    }
+
+    /// Renders every metric in the Prometheus text exposition format. `mounted` is the current number of mounted devices.
+    /// Every series is labelled with `instance`, so that several daemons can share a dashboard.
+    pub fn render(&self, mounted: usize, instance: &str) -> String {
+        let labels = format!("instance=\"{}\"", escape_label(instance));
+        let mut out = String::new();
+        out.push_str("# HELP fpvm_mounted_devices Devices currently mounted.\n");
+        out.push_str("# TYPE fpvm_mounted_devices gauge\n");
+        let _ = writeln!(out, "fpvm_mounted_devices{{{}}} {}", labels, mounted);
+        // This should be fpvm_mounted_devices + 1 for base. Anything else means our bookkeeping is off.
+        out.push_str("# HELP fpvm_union_branches Branches in the live union, including base.\n");
+        out.push_str("# TYPE fpvm_union_branches gauge\n");
+        let _ = writeln!(
+            out,
+            "fpvm_union_branches{{{}}} {}",
+            labels,
+            self.union_branches.load(Ordering::Relaxed)
+        );
+        // Every mount and unmount goes through the union lock one at a time, so these two say how much of
+        // their latency is queueing, and how much is the remount itself.
+        self.union_lock_wait.render(
+            &mut out,
+            "fpvm_union_lock_wait_seconds",
+            "Time spent waiting for the union lock.",
+            &labels,
+        );
+        self.union_lock_hold.render(
+            &mut out,
+            "fpvm_union_lock_hold_seconds",
+            "Time spent holding the union lock.",
+            &labels,
+        );
+        out.push_str("# HELP fpvm_dead_branches_evicted_total Devices taken out of the union because their fuse mount died.\n");
+        out.push_str("# TYPE fpvm_dead_branches_evicted_total counter\n");
+        let _ = writeln!(
+            out,
+            "fpvm_dead_branches_evicted_total{{{}}} {}",
+            labels,
+            self.dead_branches_evicted()
+        );
+        // Lots of 4xx on one endpoint is a misbehaving client, lots of 409s on /mount is contention.
+        {
+            let requests = self.requests.lock();
+            out.push_str(
+                "# HELP fpvm_http_requests_total HTTP requests answered, by endpoint and status.\n",
+            );
+            out.push_str("# TYPE fpvm_http_requests_total counter\n");
+            for ((endpoint, status), histogram) in requests.iter() {
+                let _ = writeln!(
+                    out,
+                    "fpvm_http_requests_total{{{},endpoint=\"{}\",status=\"{}\"}} {}",
+                    labels,
+                    endpoint,
+                    status,
+                    histogram.count.load(Ordering::Relaxed)
+                );
+            }
+            let name = "fpvm_http_request_duration_seconds";
+            Histogram::render_header(
+                &mut out,
+                name,
+                "Time taken to answer HTTP requests, by endpoint and status.",
+            );
+            for ((endpoint, status), histogram) in requests.iter() {
+                let series_labels =
+                    format!("{},endpoint=\"{}\",status=\"{}\"", labels, endpoint, status);
+                histogram.render_series(&mut out, name, &series_labels);
+            }
+        }
+        out.push_str("# HELP fpvm_subprocess_failures_total Subprocesses that didn't exit successfully, by failure kind.\n");
+        out.push_str("# TYPE fpvm_subprocess_failures_total counter\n");
+        for (err, counter) in [
+            (SubprocessError::SpawnFailed, &self.spawn_failed),
+            (SubprocessError::WaitFailed, &self.wait_failed),
+            (
+                SubprocessError::NonZeroExit { code: None },
+                &self.nonzero_exit,
+            ),
+            (SubprocessError::TimedOut, &self.timed_out),
+        ] {
+            // Writing to a String can't fail.
+            let _ = writeln!(
+                out,
+                "fpvm_subprocess_failures_total{{{},kind=\"{}\"}} {}",
+                labels,
+                err.code(),
+                counter.load(Ordering::Relaxed)
+            );
+        }
+        out
+    }
+}
+
+/// The upper bounds of the histogram buckets, in seconds. A remount takes anywhere from milliseconds
+/// to many seconds, depending on how many branches there are and how busy the host is.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// A Prometheus histogram of durations, with the bounds in `BUCKETS`.
+#[derive(Default)]
+struct Histogram {
+    /// How many observations fell in each bucket, not counting the ones below it. Cumulated when rendered.
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str, labels: &str) {
+        Histogram::render_header(out, name, help);
+        self.render_series(out, name, labels);
+    }
+
+    /// Writes the `HELP` and `TYPE` lines, which come once per metric however many series it has.
+    fn render_header(out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+    }
+
+    /// Writes this histogram's buckets, sum and count, as the series of `name` with `labels`.
+    fn render_series(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(
+            out,
+            "{}_sum{{{}}} {}",
+            name,
+            labels,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// Escapes a Prometheus label value.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,218 @@
+use std::{ffi::CString, io, os::unix::io::AsRawFd, ptr, time::Duration};
+use tokio::fs::{metadata, read_dir, read_to_string};
+use tokio::time::{sleep, Instant};
+
+/// The kernel's mount table. Tests keep a pretend one, which their stand-ins for the mount binaries write to.
+pub const MOUNT_TABLE: &str = if cfg!(test) {
+    "/tmp/fpvm-test-mounts"
+} else {
+    "/proc/mounts"
+};
+
+/// How often to check whether an unmount has finished.
+const UNMOUNT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// One entry of the kernel's mount table.
+pub struct MountInfo {
+    /// Where it's mounted.
+    pub target: String,
+    /// The filesystem type, like `fuse.unionfs`.
+    pub fstype: String,
+    /// The mount options, like `ro` and `nosuid`.
+    pub options: Vec<String>,
+}
+
+/// Reads the kernel's mount table from `/proc/mounts`.
+pub async fn read_mounts() -> std::io::Result<Vec<MountInfo>> {
+    let table = read_to_string(MOUNT_TABLE).await?;
+    Ok(table.lines().filter_map(parse_line).collect())
+}
+
+/// Parses a `/proc/mounts` line: `source target fstype options dump pass`.
+fn parse_line(line: &str) -> Option<MountInfo> {
+    let mut fields = line.split(' ');
+    let _source = fields.next()?;
+    let target = unescape(fields.next()?);
+    let fstype = unescape(fields.next()?);
+    let options = fields.next()?.split(',').map(unescape).collect();
+    Some(MountInfo {
+        target,
+        fstype,
+        options,
+    })
+}
+
+/// Undoes the octal escapes (`\040` for a space, and so on) that `/proc/mounts` uses.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let digits = bytes.get(i + 1..i + 4);
+        if bytes[i] == b'\\' {
+            if let Some(digits) = digits.filter(|d| d.iter().all(|b| (b'0'..=b'7').contains(b))) {
+                out.push(digits.iter().fold(0u8, |acc, b| (acc << 3) | (b - b'0')));
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Checks whether anything is mounted at `target`. Assumes not if the mount table can't be read.
+pub async fn is_mountpoint(target: &str) -> bool {
+    match read_mounts().await {
+        Ok(mounts) => mounts.iter().any(|mount| mount.target == target),
+        Err(_) => false,
+    }
+}
+
+/// Waits until nothing is mounted at any of `targets` any more, for at most `limit`.
+/// Returns whether they all got there in time.
+pub async fn wait_unmounted(targets: &[&str], limit: Duration) -> bool {
+    let deadline = Instant::now() + limit;
+    loop {
+        let mut mounted = false;
+        for target in targets {
+            mounted |= is_mountpoint(target).await;
+        }
+        if !mounted {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        sleep(UNMOUNT_POLL_INTERVAL).await;
+    }
+}
+
+/// Checks that a working fuse filesystem is mounted at `target`: it's in the mount table, and it can be listed.
+/// A fuse process that died (or never finished starting) leaves a mount that fails the second check.
+pub async fn is_live_fuse_mount(target: &str) -> bool {
+    let mounted = match read_mounts().await {
+        Ok(mounts) => mounts
+            .iter()
+            .any(|mount| mount.target == target && mount.fstype.starts_with("fuse")),
+        Err(_) => false,
+    };
+    mounted && read_dir(target).await.is_ok()
+}
+
+/// Checks whether `target` is a fuse mount whose process is gone. The kernel answers everything about those with ENOTCONN,
+/// including the stat that umount does before it gets anywhere.
+pub async fn is_stale(target: &str) -> bool {
+    matches!(metadata(target).await, Err(err) if err.raw_os_error() == Some(libc::ENOTCONN))
+}
+
+/// Checks that whatever is mounted at `target` is mounted read-only. If several things are stacked
+/// there, only the topmost counts, since that's the one that gets used.
+pub async fn is_read_only(target: &str) -> bool {
+    match read_mounts().await {
+        Ok(mounts) => mounts
+            .iter()
+            .rev()
+            .find(|mount| mount.target == target)
+            .is_some_and(|mount| mount.options.iter().any(|option| option == "ro")),
+        Err(_) => false,
+    }
+}
+
+/// Finds the branches the unionfs process serving `target` was started with. `/proc/mounts` doesn't have them, but
+/// the process's command line does: `unionfs <branch>:<branch>... <target> -o ...`. When the union was built
+/// somewhere else and moved into place, that's where its command line says it's mounted, so `built_at` counts too.
+/// If there's more than one (an old union that hasn't gone away yet), the newest wins. Returns `None` if there
+/// isn't one.
+pub async fn live_union_branches(target: &str, built_at: &str) -> Option<Vec<String>> {
+    let mut procs = read_dir("/proc").await.ok()?;
+    // The start time, in ticks since boot, and the branches, of the newest so far.
+    let mut newest: Option<(u64, Vec<String>)> = None;
+    while let Ok(Some(entry)) = procs.next_entry().await {
+        let path = entry.path();
+        let cmdline = match tokio::fs::read(path.join("cmdline")).await {
+            Ok(cmdline) => cmdline,
+            Err(_) => continue,
+        };
+        let args: Vec<String> = cmdline
+            .split(|&b| b == 0)
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+        if !args
+            .first()
+            .is_some_and(|program| program.ends_with("unionfs"))
+        {
+            continue;
+        }
+        let branches = match args
+            .windows(2)
+            .find(|pair| pair[1] == target || pair[1] == built_at)
+        {
+            Some(pair) => pair[0].split(':').map(str::to_owned).collect(),
+            None => continue,
+        };
+        let started = read_to_string(path.join("stat"))
+            .await
+            .ok()
+            .and_then(|stat| start_time(&stat))
+            .unwrap_or(0);
+        if newest.as_ref().is_none_or(|(newest, _)| started >= *newest) {
+            newest = Some((started, branches));
+        }
+    }
+    newest.map(|(_, branches)| branches)
+}
+
+/// Gets a process's start time out of its `/proc/<pid>/stat`. It's the 22nd field, counting from the pid, but the
+/// second one is the command name in parentheses, which can have spaces in it, so count from the last `)`.
+fn start_time(stat: &str) -> Option<u64> {
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Moves this process into the mount namespace at `path`, like `/proc/<pid>/ns/mnt`. From then on, every mount we
+/// make or look at is in there. Needs CAP_SYS_ADMIN, and has to happen before any other threads are started, since
+/// the kernel won't move a process whose threads share its filesystem state.
+pub fn enter_mount_namespace(path: &str) -> io::Result<()> {
+    let namespace = std::fs::File::open(path)?;
+    // SAFETY: the fd belongs to `namespace`, which outlives this call.
+    if unsafe { libc::setns(namespace.as_raw_fd(), libc::CLONE_NEWNS) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Replaces whatever is mounted at `target` with the mount at `source`: lazily detaches the old mount, and moves
+/// the new one into its place straight after. Doing it with two syscalls in a row, rather than two subprocesses,
+/// shrinks the time that nothing is mounted at `target` from milliseconds to next to nothing. Needs to run as root.
+/// It's fine for nothing to be mounted at `target` to begin with.
+pub async fn replace_mount(source: &str, target: &str) -> io::Result<()> {
+    let source = CString::new(source).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let target = CString::new(target).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    tokio::task::spawn_blocking(move || {
+        // SAFETY: both paths are valid NUL-terminated strings that outlive the calls, and the rest are flags or null.
+        unsafe {
+            if libc::umount2(target.as_ptr(), libc::MNT_DETACH) != 0 {
+                let err = io::Error::last_os_error();
+                // EINVAL means there was nothing mounted there.
+                if err.raw_os_error() != Some(libc::EINVAL) {
+                    return Err(err);
+                }
+            }
+            if libc::mount(
+                source.as_ptr(),
+                target.as_ptr(),
+                ptr::null(),
+                libc::MS_MOVE,
+                ptr::null(),
+            ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|_| io::Error::from(io::ErrorKind::Other))?
+}
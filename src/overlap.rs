@@ -0,0 +1,66 @@
+use log::{debug, info, warn};
+use std::{collections::HashSet, path::PathBuf};
+use tokio::fs::read_dir;
+
+/// How many colliding paths to list in the warning. The rest are only counted.
+const MAX_LISTED: usize = 20;
+
+/// Walks the files under `branches` and `others`, and warns about every relative path that's a file in both.
+/// Whichever branch comes first in the union wins, so one of the two is being hidden.
+/// Symlinks count as files and aren't followed. Unreadable folders are skipped.
+pub async fn report(label: String, branches: Vec<String>, others: Vec<String>) {
+    let mut mine = HashSet::new();
+    for branch in &branches {
+        mine.extend(relative_files(branch).await);
+    }
+    let mut collisions = Vec::new();
+    for other in &others {
+        for path in relative_files(other).await {
+            if mine.contains(&path) {
+                collisions.push(format!("{} (also in {})", path.display(), other));
+            }
+        }
+    }
+    if collisions.is_empty() {
+        info!("{} doesn't overlap any other branch", label);
+        return;
+    }
+    collisions.sort_unstable();
+    let more = collisions.len().saturating_sub(MAX_LISTED);
+    collisions.truncate(MAX_LISTED);
+    let mut listed = collisions.join(", ");
+    if more > 0 {
+        listed += &format!(" and {} more", more);
+    }
+    warn!(
+        "{} has files at the same paths as other branches: {}",
+        label, listed
+    );
+}
+
+/// Every file and symlink under `root`, relative to it.
+async fn relative_files(root: &str) -> HashSet<PathBuf> {
+    let mut files = HashSet::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(rel) = pending.pop() {
+        let dir = PathBuf::from(root).join(&rel);
+        let mut entries = match read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                debug!("Not checking {} for overlaps: {}", dir.display(), err);
+                continue;
+            }
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = rel.join(entry.file_name());
+            match entry.file_type().await {
+                Ok(file_type) if file_type.is_dir() => pending.push(path),
+                Ok(_) => {
+                    files.insert(path);
+                }
+                Err(_) => {}
+            }
+        }
+    }
+    files
+}
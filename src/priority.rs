@@ -0,0 +1,127 @@
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use tokio::sync::oneshot;
+
+/// How urgently a mount is wanted.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Somebody is waiting on it, like a game the user just started.
+    High,
+    /// Nobody is waiting on it yet, like a prefetch.
+    Normal,
+}
+
+impl Priority {
+    /// Parses the `priority` query param.
+    pub fn parse(value: &str) -> Option<Priority> {
+        match value {
+            "high" => Some(Priority::High),
+            "normal" => Some(Priority::Normal),
+            _ => None,
+        }
+    }
+}
+
+/// A counting semaphore that hands its permits to high-priority waiters before normal ones. Within a priority,
+/// waiters are served first come, first served.
+pub struct PrioritySemaphore {
+    state: Mutex<State>,
+}
+
+struct State {
+    available: usize,
+    high: VecDeque<oneshot::Sender<()>>,
+    normal: VecDeque<oneshot::Sender<()>>,
+}
+
+impl PrioritySemaphore {
+    /// A semaphore with `permits` permits.
+    pub fn new(permits: usize) -> PrioritySemaphore {
+        PrioritySemaphore {
+            state: Mutex::new(State {
+                available: permits,
+                high: VecDeque::new(),
+                normal: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Waits for a permit, which is given back when the returned guard is dropped.
+    pub async fn acquire(&self, priority: Priority) -> Permit<'_> {
+        let receiver = {
+            let mut state = self.state.lock();
+            // Nobody queued ahead of us can be waiting if there's a permit free, so take it.
+            if state.available > 0 {
+                state.available -= 1;
+                return Permit { semaphore: self };
+            }
+            let (sender, receiver) = oneshot::channel();
+            match priority {
+                Priority::High => state.high.push_back(sender),
+                Priority::Normal => state.normal.push_back(sender),
+            }
+            receiver
+        };
+        let mut waiter = Waiter {
+            semaphore: self,
+            receiver,
+            granted: false,
+        };
+        // The sender is only ever dropped after sending, so this can't fail.
+        let _ = (&mut waiter.receiver).await;
+        waiter.granted = true;
+        Permit { semaphore: self }
+    }
+
+    /// Passes a permit on to the next waiter, or puts it back if there isn't one.
+    fn release(&self) {
+        let mut state = self.state.lock();
+        loop {
+            let next = match state.high.pop_front() {
+                Some(sender) => sender,
+                None => match state.normal.pop_front() {
+                    Some(sender) => sender,
+                    None => {
+                        state.available += 1;
+                        return;
+                    }
+                },
+            };
+            // A waiter that gave up has dropped its receiver. Try the one after it.
+            if next.send(()).is_ok() {
+                return;
+            }
+        }
+    }
+}
+
+/// One of a `PrioritySemaphore`'s permits. It goes back when this is dropped.
+pub struct Permit<'a> {
+    semaphore: &'a PrioritySemaphore,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
+
+/// A queued `acquire`. If it's cancelled just after being handed a permit, the permit is passed on rather than lost.
+struct Waiter<'a> {
+    semaphore: &'a PrioritySemaphore,
+    receiver: oneshot::Receiver<()>,
+    granted: bool,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        if self.granted {
+            return;
+        }
+        // Closing first means nobody can send to us after we've checked.
+        self.receiver.close();
+        if self.receiver.try_recv().is_ok() {
+            self.semaphore.release();
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// How much of a device to read when probing. Enough to reach the ISO 9660 volume descriptor.
+const PROBE_LEN: u64 = 32774;
+
+/// An on-disk format that we recognise from its magic bytes.
+#[derive(Clone, Copy)]
+pub enum Format {
+    Zip,
+    SevenZip,
+    Rar,
+    Tar,
+    Gzip,
+    Bzip2,
+    Xz,
+    Iso9660,
+    Squashfs,
+    Ext,
+}
+
+impl Format {
+    /// A short machine-readable name for this format.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Zip => "zip",
+            Format::SevenZip => "7z",
+            Format::Rar => "rar",
+            Format::Tar => "tar",
+            Format::Gzip => "gzip",
+            Format::Bzip2 => "bzip2",
+            Format::Xz => "xz",
+            Format::Iso9660 => "iso9660",
+            Format::Squashfs => "squashfs",
+            Format::Ext => "ext",
+        }
+    }
+
+    /// Whether fuse-archive can mount this format.
+    pub fn archive_mountable(&self) -> bool {
+        !matches!(self, Format::Squashfs | Format::Ext)
+    }
+
+    /// Whether this can be mounted without raw mode: by fuse-archive, or by squashfuse for squashfs.
+    pub fn mountable(&self) -> bool {
+        self.archive_mountable() || matches!(self, Format::Squashfs)
+    }
+
+    /// Whether this is a filesystem image, which can be mounted in raw mode instead.
+    pub fn raw_mountable(&self) -> bool {
+        matches!(self, Format::Ext | Format::Iso9660 | Format::Squashfs)
+    }
+}
+
+/// Reads the start of a device and works out its format from the magic bytes. `None` if nothing matched.
+pub async fn detect_format(path: &str) -> std::io::Result<Option<Format>> {
+    let mut head = Vec::new();
+    File::open(path)
+        .await?
+        .take(PROBE_LEN)
+        .read_to_end(&mut head)
+        .await?;
+    Ok(match_magic(&head))
+}
+
+/// Matches the magic bytes at the start of `head` (or wherever the format keeps them).
+fn match_magic(head: &[u8]) -> Option<Format> {
+    let at = |offset: usize, magic: &[u8]| head.get(offset..offset + magic.len()) == Some(magic);
+    if at(0, b"PK\x03\x04") || at(0, b"PK\x05\x06") {
+        Some(Format::Zip)
+    } else if at(0, b"7z\xBC\xAF\x27\x1C") {
+        Some(Format::SevenZip)
+    } else if at(0, b"Rar!\x1A\x07") {
+        Some(Format::Rar)
+    } else if at(0, b"\x1F\x8B") {
+        Some(Format::Gzip)
+    } else if at(0, b"BZh") {
+        Some(Format::Bzip2)
+    } else if at(0, b"\xFD7zXZ\x00") {
+        Some(Format::Xz)
+    } else if at(0, b"hsqs") {
+        Some(Format::Squashfs)
+    } else if at(257, b"ustar") {
+        Some(Format::Tar)
+    } else if at(1080, b"\x53\xEF") {
+        Some(Format::Ext)
+    } else if at(32769, b"CD001") {
+        Some(Format::Iso9660)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+use tokio::time::sleep;
+
+/// Runs `f` until it succeeds or has been tried `attempts` times, and returns its last result.
+/// `f` is given the attempt number, starting at 1. Before each retry we wait for `base_delay`,
+/// doubled for every retry since the first, with up to half of it taken off at random so that
+/// callers which failed together don't all retry together.
+pub async fn retry_with_backoff<T, E, F, G>(
+    attempts: u32,
+    base_delay: Duration,
+    f: F,
+) -> Result<T, E>
+where
+    F: FnMut(u32) -> G,
+    G: Future<Output = Result<T, E>>,
+{
+    retry_with_backoff_if(attempts, base_delay, |_| true, f).await
+}
+
+/// Like `retry_with_backoff`, but only retries errors that `retryable` says are worth another try. Any other error
+/// is returned straight away.
+pub async fn retry_with_backoff_if<T, E, R, F, G>(
+    attempts: u32,
+    base_delay: Duration,
+    retryable: R,
+    mut f: F,
+) -> Result<T, E>
+where
+    R: Fn(&E) -> bool,
+    F: FnMut(u32) -> G,
+    G: Future<Output = Result<T, E>>,
+{
+    let attempts = attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        match f(attempt).await {
+            Err(err) if attempt < attempts && retryable(&err) => {
+                sleep(backoff_delay(base_delay, attempt)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// How long to wait after failed attempt number `attempt`.
+fn backoff_delay(base_delay: Duration, attempt: u32) -> Duration {
+    let delay = base_delay.saturating_mul(1 << (attempt - 1).min(16));
+    // RandomState is seeded randomly, which is all the randomness this needs.
+    let jitter = (RandomState::new().build_hasher().finish() % 1024) as f64 / 1023.0;
+    delay.mul_f64(1.0 - jitter / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::{pause, Instant};
+
+    #[tokio::test]
+    async fn waits_longer_after_each_failure() {
+        pause();
+        let base_delay = Duration::from_millis(100);
+        let mut calls = Vec::new();
+        let _: Result<(), ()> = retry_with_backoff(5, base_delay, |_| {
+            calls.push(Instant::now());
+            async { Err(()) }
+        })
+        .await;
+        for (retry, pair) in calls.windows(2).enumerate() {
+            let waited = pair[1] - pair[0];
+            let full = base_delay * (1 << retry);
+            assert!(waited <= full, "retry {} waited {:?}", retry + 1, waited);
+            assert!(
+                waited >= full / 2,
+                "retry {} waited {:?}",
+                retry + 1,
+                waited
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        pause();
+        let mut calls = 0;
+        let result: Result<(), u32> =
+            retry_with_backoff(4, Duration::from_millis(100), |attempt| {
+                calls += 1;
+                async move { Err(attempt) }
+            })
+            .await;
+        assert_eq!(calls, 4);
+        assert_eq!(result, Err(4));
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_success() {
+        pause();
+        let mut calls = 0;
+        let result: Result<u32, ()> =
+            retry_with_backoff(5, Duration::from_millis(100), |attempt| {
+                calls += 1;
+                async move {
+                    match attempt {
+                        3 => Ok(attempt),
+                        _ => Err(()),
+                    }
+                }
+            })
+            .await;
+        assert_eq!(calls, 3);
+        assert_eq!(result, Ok(3));
+    }
+
+    #[tokio::test]
+    async fn always_tries_at_least_once() {
+        pause();
+        let mut calls = 0;
+        let result: Result<(), ()> = retry_with_backoff(0, Duration::from_millis(100), |_| {
+            calls += 1;
+            async { Err(()) }
+        })
+        .await;
+        assert_eq!(calls, 1);
+        assert_eq!(result, Err(()));
+    }
+
+    #[tokio::test]
+    async fn only_retries_what_its_told_to() {
+        pause();
+        let mut calls = 0;
+        let result: Result<(), u32> = retry_with_backoff_if(
+            5,
+            Duration::from_millis(100),
+            |err| *err < 2,
+            |attempt| {
+                calls += 1;
+                async move { Err(attempt) }
+            },
+        )
+        .await;
+        assert_eq!(calls, 2);
+        assert_eq!(result, Err(2));
+    }
+
+    #[test]
+    fn jitter_takes_off_at_most_half() {
+        let base_delay = Duration::from_millis(100);
+        for _ in 0..1000 {
+            let delay = backoff_delay(base_delay, 3);
+            assert!(delay <= base_delay * 4);
+            assert!(delay >= base_delay * 2);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use crate::{config::Config, LockedMountStatus};
+use log::{debug, error, warn};
+use parking_lot::Mutex;
+use std::{
+    future::Future,
+    hash::BuildHasher,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    time::{sleep, sleep_until, Instant, Sleep},
+};
+use warp::hyper::{server::conn::Http, service::service_fn, service::Service};
+
+/// The address a request came from, as a request extension. `warp::addr::remote` only works with `warp::serve`,
+/// which can't time connections out.
+#[derive(Clone, Copy)]
+pub struct Peer(pub SocketAddr);
+
+/// Serves `service` on `addr` until the process exits, like `warp::serve` would, but drops connections that take
+/// longer than the configured timeouts to send a request's headers, or that sit idle between requests for too long.
+/// Those are read from the configuration for each new connection, so a reload applies to connections made after it.
+/// hyper has a header timeout of its own, but the version warp pulls in never actually fires it.
+pub async fn serve<T, S>(service: S, addr: SocketAddr, shared_state: Arc<LockedMountStatus<T>>)
+where
+    T: BuildHasher + Send + Sync + 'static,
+    S: Service<warp::http::Request<warp::hyper::Body>, Response = warp::reply::Response>
+        + Clone
+        + Send
+        + 'static,
+    S::Error: std::error::Error + Send + Sync + 'static,
+    S::Future: Send + 'static,
+{
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Could not listen on {}: {}", addr, err);
+            std::process::exit(1);
+        }
+    };
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Most likely out of file descriptors. Give some connections a chance to close.
+                warn!("Could not accept a connection: {}", err);
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let config = shared_state.config();
+        let activity = Arc::new(Activity::default());
+        let io = Deadlines::new(stream, &config, Arc::clone(&activity));
+        let service = service.clone();
+        let handler = service_fn(move |mut request| {
+            request.extensions_mut().insert(Peer(peer));
+            let busy = Busy::new(&activity);
+            let response = service.clone().call(request);
+            async move {
+                let response = response.await;
+                drop(busy);
+                response
+            }
+        });
+        tokio::spawn(async move {
+            if let Err(err) = Http::new().serve_connection(io, handler).await {
+                debug!("Connection from {} ended: {}", peer, err);
+            }
+        });
+    }
+}
+
+/// What a connection's requests are up to, shared between its reads and its handlers.
+#[derive(Default)]
+struct Activity {
+    state: Mutex<ActivityState>,
+}
+
+#[derive(Default)]
+struct ActivityState {
+    /// How many requests are being handled right now.
+    handling: usize,
+    /// How many requests have been handled, so that a read can tell that one finished since it last looked.
+    finished: u64,
+    /// The read that's waiting for the handlers to finish, so it can start timing the connection again.
+    reader: Option<Waker>,
+}
+
+/// Counts a request as being handled for as long as it's alive.
+struct Busy(Arc<Activity>);
+
+impl Busy {
+    fn new(activity: &Arc<Activity>) -> Busy {
+        activity.state.lock().handling += 1;
+        Busy(Arc::clone(activity))
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.handling -= 1;
+        state.finished += 1;
+        if state.handling == 0 {
+            if let Some(reader) = state.reader.take() {
+                reader.wake();
+            }
+        }
+    }
+}
+
+/// A connection whose reads fail once it's been idle for too long, or once a request's headers have taken too long
+/// to arrive. Neither clock runs while a request is being handled: the client is allowed to go quiet while it waits
+/// for an answer, which can take as long as a mount does.
+struct Deadlines {
+    stream: TcpStream,
+    idle_limit: Option<Duration>,
+    header_limit: Option<Duration>,
+    activity: Arc<Activity>,
+    /// The number of finished requests when we last looked.
+    finished: u64,
+    /// Whether part of a request has arrived since the last one was handled.
+    partial: bool,
+    deadline: Option<Instant>,
+    timer: Pin<Box<Sleep>>,
+}
+
+impl Deadlines {
+    fn new(stream: TcpStream, config: &Config, activity: Arc<Activity>) -> Deadlines {
+        let now = Instant::now();
+        Deadlines {
+            stream,
+            idle_limit: config.http_idle_timeout,
+            header_limit: config.http_header_timeout,
+            activity,
+            finished: 0,
+            partial: false,
+            deadline: config.http_idle_timeout.map(|limit| now + limit),
+            timer: Box::pin(sleep_until(now)),
+        }
+    }
+}
+
+impl AsyncRead for Deadlines {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let filled = buf.filled().len();
+        let read = Pin::new(&mut this.stream).poll_read(cx, buf);
+        let got_data = buf.filled().len() > filled;
+        {
+            let mut state = this.activity.state.lock();
+            if state.handling > 0 {
+                // Nothing to time until the handlers are done. Have them wake us when they are.
+                state.reader = Some(cx.waker().clone());
+                return read;
+            }
+            // A request was handled since we last looked, so the connection has been idle since just now.
+            if state.finished != this.finished {
+                this.finished = state.finished;
+                this.partial = false;
+                this.deadline = this.idle_limit.map(|limit| Instant::now() + limit);
+            }
+        }
+        // The start of a new request. Its headers have to be in before long.
+        if got_data && !this.partial {
+            this.partial = true;
+            this.deadline = this.header_limit.map(|limit| Instant::now() + limit);
+        }
+        if read.is_ready() {
+            return read;
+        }
+        let deadline = match this.deadline {
+            Some(deadline) => deadline,
+            None => return Poll::Pending,
+        };
+        if this.timer.deadline() != deadline {
+            this.timer.as_mut().reset(deadline);
+        }
+        match this.timer.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::ErrorKind::TimedOut.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for Deadlines {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,210 @@
+use crate::{config::Config, HTTPResponse};
+use log::warn;
+use std::{cell::RefCell, ffi::CString, future::Future, process::Stdio, time::Duration};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::time::timeout;
+
+/// How much of each stream of each subprocess to keep when capturing output.
+const CAPTURE_LIMIT: usize = 4096;
+/// How long to wait for a subprocess's output once it's exited. A fuse binary daemonizes, and its
+/// daemon half could in principle keep the pipes open for as long as it runs.
+const CAPTURE_WAIT: Duration = Duration::from_millis(200);
+
+tokio::task_local! {
+    /// What the subprocesses run for a verbose request printed. Only set while one is being handled.
+    static CAPTURED: RefCell<Vec<String>>;
+}
+
+/// Runs `op`, and collects what every mount binary it runs prints, stdout and stderr alike.
+/// Outside of this, their output isn't even piped, so nobody pays for it unless they ask.
+pub async fn capture_output<F: Future>(op: F) -> (F::Output, Vec<String>) {
+    CAPTURED
+        .scope(RefCell::new(Vec::new()), async {
+            let result = op.await;
+            (result, CAPTURED.with(|captured| captured.take()))
+        })
+        .await
+}
+
+fn capturing() -> bool {
+    CAPTURED.try_with(|_| ()).is_ok()
+}
+
+/// Adds a line to the output being captured, if any is.
+fn capture(line: String) {
+    let _ = CAPTURED.try_with(|captured| captured.borrow_mut().push(line));
+}
+
+/// Reads what's left in one of a subprocess's output streams, up to `CAPTURE_LIMIT` bytes.
+async fn read_capped(stream: Option<impl AsyncRead + Unpin>) -> String {
+    let mut buf = Vec::new();
+    if let Some(stream) = stream {
+        let _ = timeout(
+            CAPTURE_WAIT,
+            stream.take(CAPTURE_LIMIT as u64).read_to_end(&mut buf),
+        )
+        .await;
+    }
+    String::from_utf8_lossy(&buf).trim_end().to_owned()
+}
+
+/// Why a subprocess didn't run to a successful exit.
+#[derive(Clone, Copy, Debug)]
+pub enum SubprocessError {
+    /// The binary couldn't be started at all. This usually means a misconfigured binary path.
+    SpawnFailed,
+    /// The process started, but we couldn't read its exit status.
+    WaitFailed,
+    /// The process exited unsuccessfully. `code` is `None` if it was killed by a signal.
+    NonZeroExit { code: Option<i32> },
+    /// The process didn't exit within the subprocess timeout, and has been killed.
+    TimedOut,
+}
+
+impl SubprocessError {
+    /// A short machine-readable name for this failure, used for metrics.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SubprocessError::SpawnFailed => "spawn_failed",
+            SubprocessError::WaitFailed => "wait_failed",
+            SubprocessError::NonZeroExit { .. } => "nonzero_exit",
+            SubprocessError::TimedOut => "timed_out",
+        }
+    }
+
+    /// The response to send a client whose request failed because of this. Other than timeouts, these are
+    /// all down to the host rather than to us, so they're 503s with an `environment_` code.
+    pub fn to_response(self) -> HTTPResponse {
+        match self {
+            SubprocessError::SpawnFailed => HTTPResponse {
+                status: 503,
+                code: "environment_spawn_failed",
+                body: "Could not spawn subprocess.".to_owned(),
+            },
+            SubprocessError::WaitFailed => HTTPResponse {
+                status: 503,
+                code: "environment_wait_failed",
+                body: "Could not read subprocess status.".to_owned(),
+            },
+            SubprocessError::NonZeroExit { code: Some(code) } => HTTPResponse {
+                status: 503,
+                code: "environment_nonzero_exit",
+                body: format!("Subprocess exited with an unsuccessful status: {}", code),
+            },
+            SubprocessError::NonZeroExit { code: None } => HTTPResponse {
+                status: 503,
+                code: "environment_nonzero_exit",
+                body: "Subprocess was killed by a signal.".to_owned(),
+            },
+            SubprocessError::TimedOut => HTTPResponse {
+                status: 504,
+                code: self.code(),
+                body: "Subprocess timed out.".to_owned(),
+            },
+        }
+    }
+}
+
+/// Builds a command for one of the mount binaries, run through the privilege wrapper if one is configured.
+/// If output is being captured, its stdout and stderr are piped for `wait_subprocess` to collect.
+pub fn privileged(config: &Config, program: &str) -> Command {
+    let mut command = match config.priv_wrapper.split_first() {
+        // (sudo -n) /bin/umount ...
+        Some((wrapper, wrapper_args)) => {
+            let mut command = Command::new(wrapper);
+            command.args(wrapper_args).arg(program);
+            command
+        }
+        None => Command::new(program),
+    };
+    if config.subprocess_nice != 0 || config.subprocess_cgroup.is_some() {
+        deprioritize(&mut command, config);
+    }
+    if capturing() {
+        capture(format!("{}:", program));
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+    command
+}
+
+/// Makes `command` run at the configured niceness, and in the configured cgroup. Whatever it starts inherits both.
+/// Failing to apply either isn't worth failing a mount over, so it runs regardless.
+fn deprioritize(command: &mut Command, config: &Config) {
+    let nice = config.subprocess_nice;
+    let procs = config
+        .subprocess_cgroup
+        .as_ref()
+        .and_then(|cgroup| CString::new(cgroup.clone() + "/cgroup.procs").ok());
+    // SAFETY: between fork and exec, only async-signal-safe calls are allowed. setpriority, open, write and close
+    // are, and everything they're given was allocated before the fork.
+    unsafe {
+        command.pre_exec(move || {
+            if nice != 0 {
+                libc::setpriority(libc::PRIO_PROCESS, 0, nice);
+            }
+            if let Some(procs) = &procs {
+                // Writing 0 moves the writer itself.
+                let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                if fd >= 0 {
+                    libc::write(fd, b"0".as_ptr().cast(), 1);
+                    libc::close(fd);
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Forwards each line a subprocess writes to stderr into our log, prefixed with `label`.
+/// Does nothing unless the child was spawned with a piped stderr.
+pub fn log_stderr(child: &mut Child, label: String) {
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                warn!("{}: {}", label, line);
+            }
+        });
+    }
+}
+
+/// Wait for a process to spawn and exit successfully. If `limit` is set, the process is killed once it runs longer than that.
+pub async fn wait_subprocess(
+    spawnedproc: std::io::Result<Child>,
+    limit: Option<Duration>,
+) -> Result<(), SubprocessError> {
+    // Did it spawn successfully?
+    let mut child = spawnedproc.map_err(|_| SubprocessError::SpawnFailed)?;
+    // Yup, wait for it to complete.
+    let status = match limit {
+        Some(limit) => match timeout(limit, child.wait()).await {
+            Ok(status) => status,
+            Err(_) => {
+                // Don't leave it running in the background. There's nothing more we can do if killing fails.
+                let _ = child.kill().await;
+                return Err(SubprocessError::TimedOut);
+            }
+        },
+        None => child.wait().await,
+    };
+    // Hang on to whatever it printed, if anyone asked. Streams that were taken elsewhere (or never piped) are skipped.
+    if capturing() {
+        for (stream, text) in [
+            ("stdout", read_capped(child.stdout.take()).await),
+            ("stderr", read_capped(child.stderr.take()).await),
+        ] {
+            if !text.is_empty() {
+                capture(format!("[{}] {}", stream, text));
+            }
+        }
+    }
+    // Check that it was successful.
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(SubprocessError::NonZeroExit {
+            code: status.code(),
+        }),
+        Err(_) => Err(SubprocessError::WaitFailed),
+    }
+}
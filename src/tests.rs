@@ -0,0 +1,630 @@
+use super::*;
+use crate::util::SCHEMA_VERSION;
+use fnv::FnvBuildHasher;
+use std::path::PathBuf;
+
+/// Stub tests share the pretend mount table, and the union, so they take turns.
+static MOUNT_TABLE_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// A stand-in for the mount binaries, run as the privilege wrapper. It logs every command it's given, one per line,
+/// holds the ones with `hold` in them until it's released, and fails the ones with `fail` in them. The rest do to the pretend mount table what the real ones would do to the
+/// real one: unionfs adds its union, and umount and fusermount take away whatever they're given.
+struct Stub {
+    log: PathBuf,
+    fail: Option<&'static str>,
+    hold: Option<&'static str>,
+    _table: tokio::sync::MutexGuard<'static, ()>,
+}
+
+impl Stub {
+    /// A stub whose commands are logged under `name`, which has to be unique to the test. Waits for any other test
+    /// using a stub to finish, then starts it off with an empty mount table.
+    async fn new(name: &str, fail: Option<&'static str>) -> Stub {
+        let table = MOUNT_TABLE_LOCK.lock().await;
+        std::fs::write(mounts::MOUNT_TABLE, "").expect("mount table");
+        std::fs::create_dir_all(BASE_DIR).expect("base dir");
+        let log = PathBuf::from(format!("{}fpvm-test-{}.log", TMP_DIR, name));
+        let _ = std::fs::remove_file(&log);
+        let _ = std::fs::remove_file(log.with_extension("release"));
+        Stub {
+            log,
+            fail,
+            hold: None,
+            _table: table,
+        }
+    }
+
+    /// Makes the stub wait for `release` before running any command with `hold` in it.
+    fn holding(mut self, hold: &'static str) -> Stub {
+        self.hold = Some(hold);
+        self
+    }
+
+    /// Lets held commands carry on.
+    fn release(&self) {
+        std::fs::write(self.log.with_extension("release"), "").expect("release");
+    }
+
+    /// Waits until the stub has been given `command`.
+    async fn until_called(&self, command: &str) {
+        while !self.calls().iter().any(|call| call == command) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// The default configuration, with the stub as the privilege wrapper.
+    fn config(&self) -> Config {
+        let mut script = format!("echo \"$*\" >> '{}'\n", self.log.display());
+        if let Some(hold) = self.hold {
+            script += &format!(
+                "case \"$*\" in *'{}'*) until [ -e '{}' ]; do sleep 0.01; done ;; esac\n",
+                hold,
+                self.log.with_extension("release").display()
+            );
+        }
+        if let Some(fail) = self.fail {
+            script += &format!("case \"$*\" in *'{}'*) exit 1 ;; esac\n", fail);
+        }
+        let table = mounts::MOUNT_TABLE;
+        script += &format!(
+            concat!(
+                "bin=${{1##*/}}\n",
+                "shift\n",
+                "case $bin in\n",
+                "unionfs) mkdir -p \"$2\" && echo \"unionfs $2 fuse.unionfs rw 0 0\" >> '{table}' ;;\n",
+                "umount|fusermount) for target; do :; done\n",
+                "  grep -v -F \" $target \" '{table}' > '{table}.new'; mv '{table}.new' '{table}' ;;\n",
+                "esac\n",
+            ),
+            table = table
+        );
+        let mut config = Config::load(None).expect("default config");
+        config.priv_wrapper = vec![
+            "/bin/sh".to_owned(),
+            "-c".to_owned(),
+            script,
+            "stub".to_owned(),
+        ];
+        config
+    }
+
+    /// Every command the stub has been given so far, oldest first.
+    fn calls(&self) -> Vec<String> {
+        std::fs::read_to_string(&self.log)
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Creates a device's mountpoints, and puts its fuse layers in the mount table, as though it had been mounted.
+    fn mount_layers(&self, device_name: &str) {
+        let (zip_mountpt, fuzzy_mountpt, _) = mountpoints(device_name);
+        let mut table = std::fs::read_to_string(mounts::MOUNT_TABLE).expect("mount table");
+        for (mountpt, fstype) in [
+            (&zip_mountpt, "fuse.fuse-archive"),
+            (&fuzzy_mountpt, "fuse.fuzzyfs"),
+        ] {
+            std::fs::create_dir_all(mountpt).expect("mountpoint");
+            table += &format!("stub {} {} ro 0 0\n", mountpt, fstype);
+        }
+        std::fs::write(mounts::MOUNT_TABLE, table).expect("mount table");
+    }
+
+    /// Every mountpoint in the mount table.
+    fn mounted(&self) -> Vec<String> {
+        std::fs::read_to_string(mounts::MOUNT_TABLE)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split(' ').nth(1).map(str::to_owned))
+            .collect()
+    }
+}
+
+/// Shared state for a daemon with nothing mounted yet.
+fn state(config: Config) -> Arc<LockedMountStatus<FnvBuildHasher>> {
+    Arc::new(LockedMountStatus {
+        status: Mutex::new(MountStatus {
+            mounted: FnvHashMap::default(),
+            changing: FnvHashMap::default(),
+            branches: Vec::new(),
+            failures: FnvHashMap::default(),
+        }),
+        union: tokio::sync::Mutex::new(0),
+        mount_slots: config.max_concurrent_mounts.map(PrioritySemaphore::new),
+        umount_slots: config.max_concurrent_umounts.map(PrioritySemaphore::new),
+        history: History::new(config.history_size),
+        events: Events::default(),
+        rebuilds: Mutex::default(),
+        breaker: Breaker::default(),
+        config: RwLock::new(Arc::new(config)),
+        metrics: Metrics::default(),
+        unionfs_version: None,
+        union_remount: false,
+        mount_capable: true,
+        base_dir_present: AtomicBool::new(true),
+        allow_other: AllowOther {
+            user_allow_other: false,
+            usable: true,
+        },
+        faults: Faults::default(),
+    })
+}
+
+/// Records `device_name` as mounted from `device`, with its content folder as its only branch, as though a mount of
+/// it had gone through. Nothing is actually mounted.
+fn record_mounted(
+    shared_state: &Arc<LockedMountStatus<FnvBuildHasher>>,
+    device_name: &str,
+    device: &str,
+) {
+    let (_, _, content) = mountpoints(device_name);
+    let mut mount_status = shared_state.status.lock();
+    mount_status.branches.insert(0, content.clone());
+    mount_status.mounted.insert(
+        content.clone(),
+        MountEntry {
+            devname: device_name.to_owned(),
+            device: device.to_owned(),
+            branches: vec![content],
+            mounted_at: SystemTime::now(),
+            raw: false,
+            _device_lock: None,
+            _staged: None,
+            warm: None,
+            visible: true,
+            pending_rebuild: false,
+        },
+    );
+}
+
+/// Lets other tasks run until the operation on `device_name` is waiting for a slot.
+async fn until_queued(shared_state: &Arc<LockedMountStatus<FnvBuildHasher>>, device_name: &str) {
+    let (_, _, content) = mountpoints(device_name);
+    loop {
+        let queued = shared_state
+            .status
+            .lock()
+            .changing
+            .get(&content)
+            .is_some_and(|inflight| matches!(inflight.phase, Phase::WaitingForSlot));
+        if queued {
+            return;
+        }
+        tokio::task::yield_now().await;
+    }
+}
+
+#[tokio::test]
+async fn cleanup_unmounts_archive_when_fuzzyfs_fails() {
+    let stub = Stub::new("cleanup-fuzzy-busy", Some("/fuzzy")).await;
+    let shared_state = state(stub.config());
+    let (zip_mountpt, fuzzy_mountpt, content) = mountpoints("fpvm-test-cleanup-fuzzy-busy");
+
+    let err = cleanup_mount(&shared_state, &zip_mountpt, &fuzzy_mountpt, &content).await;
+
+    match err {
+        Some(MountError::UnmountFailed(failures)) => {
+            let layers: Vec<&str> = failures.iter().map(|(layer, _)| *layer).collect();
+            assert_eq!(layers, ["fuzzyfs"]);
+        }
+        _ => panic!("expected UnmountFailed"),
+    }
+    assert_eq!(
+        stub.calls(),
+        [
+            format!("{} {}", UMOUNT, fuzzy_mountpt),
+            format!("{} {}", UMOUNT, zip_mountpt)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn cleanup_reports_every_failed_unmount() {
+    let stub = Stub::new("cleanup-both-busy", Some(UMOUNT))
+        .await
+        .holding("/zip");
+    let shared_state = state(stub.config());
+    let (zip_mountpt, fuzzy_mountpt, content) = mountpoints("fpvm-test-cleanup-both-busy");
+    shared_state.status.lock().changing.insert(
+        content.clone(),
+        InFlight::new("umount", Phase::UnmountingFuzzyfs),
+    );
+
+    let zip_umount = format!("{} {}", UMOUNT, zip_mountpt);
+
+    let cleanup = tokio::spawn({
+        let shared_state = Arc::clone(&shared_state);
+        let content = content.clone();
+        async move { cleanup_mount(&shared_state, &zip_mountpt, &fuzzy_mountpt, &content).await }
+    });
+    // The fuzzyfs unmount has failed by now, and the device has to stay claimed while the archive is unmounted.
+    stub.until_called(&zip_umount).await;
+    assert!(shared_state.status.lock().changing.contains_key(&content));
+    stub.release();
+    let err = cleanup.await.expect("cleanup");
+
+    assert!(!shared_state.status.lock().changing.contains_key(&content));
+    match err {
+        Some(MountError::UnmountFailed(failures)) => {
+            let layers: Vec<&str> = failures.iter().map(|(layer, _)| *layer).collect();
+            assert_eq!(layers, ["fuzzyfs", "fuse-archive"]);
+        }
+        _ => panic!("expected UnmountFailed"),
+    }
+}
+
+#[test]
+fn fuzzy_suffixed_devname_has_its_own_mountpoints() {
+    let (zip, fuzzy, content) = mountpoints("sdb");
+    let (other_zip, other_fuzzy, other_content) = mountpoints("sdb.fuzzy");
+    for ours in [&zip, &fuzzy, &content] {
+        for theirs in [&other_zip, &other_fuzzy, &other_content] {
+            assert!(
+                !Path::new(ours).starts_with(theirs),
+                "{} is in {}",
+                ours,
+                theirs
+            );
+            assert!(
+                !Path::new(theirs).starts_with(ours),
+                "{} is in {}",
+                theirs,
+                ours
+            );
+        }
+    }
+    assert!(!Path::new(&device_dir("sdb.fuzzy")).starts_with(device_dir("sdb")));
+}
+
+#[tokio::test]
+async fn umount_during_mount_is_a_conflict() {
+    let device_name = "fpvm-test-mount-then-umount";
+    let mut config = Config::load(None).expect("default config");
+    config.max_concurrent_mounts = Some(1);
+    let shared_state = state(config);
+    // Take the only slot, so that the mount stays in flight.
+    let slots = shared_state.mount_slots.as_ref().expect("mount slots");
+    let _slot = slots.acquire(Priority::Normal).await;
+    let mount = tokio::spawn(mount_and_record(
+        device_name.to_owned(),
+        FnvHashMap::default(),
+        Arc::clone(&shared_state),
+    ));
+    until_queued(&shared_state, device_name).await;
+
+    let response = umount_and_record(
+        device_name.to_owned(),
+        FnvHashMap::default(),
+        Arc::clone(&shared_state),
+    )
+    .await;
+
+    assert_eq!((response.status, response.code), (409, "in_progress"));
+    mount.abort();
+}
+
+#[tokio::test]
+async fn mount_during_umount_is_a_conflict() {
+    let device_name = "fpvm-test-umount-then-mount";
+    let mut config = Config::load(None).expect("default config");
+    config.max_concurrent_umounts = Some(1);
+    let shared_state = state(config);
+    record_mounted(
+        &shared_state,
+        device_name,
+        "/dev/fpvm-test-umount-then-mount",
+    );
+    // Take the only slot, so that the unmount stays in flight.
+    let slots = shared_state.umount_slots.as_ref().expect("umount slots");
+    let _slot = slots.acquire(Priority::Normal).await;
+    let umount = tokio::spawn(umount_and_record(
+        device_name.to_owned(),
+        FnvHashMap::default(),
+        Arc::clone(&shared_state),
+    ));
+    until_queued(&shared_state, device_name).await;
+
+    let response = mount_and_record(
+        device_name.to_owned(),
+        FnvHashMap::default(),
+        Arc::clone(&shared_state),
+    )
+    .await;
+
+    assert_eq!((response.status, response.code), (409, "in_progress"));
+    umount.abort();
+}
+
+#[tokio::test]
+async fn mounting_an_alias_of_a_mounted_device_is_a_conflict() {
+    std::fs::create_dir_all(DEV_LOCATION).expect("device dir");
+    let device = DEV_LOCATION.to_owned() + "fpvm-test-aliased";
+    let alias = DEV_LOCATION.to_owned() + "fpvm-test-alias";
+    std::fs::write(&device, b"").expect("device");
+    let _ = std::fs::remove_file(&alias);
+    std::os::unix::fs::symlink(&device, &alias).expect("alias");
+    let device = std::fs::canonicalize(&device)
+        .expect("resolved device")
+        .display()
+        .to_string();
+    let shared_state = state(Config::load(None).expect("default config"));
+    record_mounted(&shared_state, "fpvm-test-aliased", &device);
+
+    let response = mount_and_record(
+        "fpvm-test-alias".to_owned(),
+        FnvHashMap::default(),
+        Arc::clone(&shared_state),
+    )
+    .await;
+
+    assert_eq!((response.status, response.code), (409, "device_in_use"));
+    // The refused mount mustn't leave its claim behind.
+    let (_, _, content) = mountpoints("fpvm-test-alias");
+    assert!(!shared_state.status.lock().changing.contains_key(&content));
+}
+
+#[tokio::test]
+async fn umount_works_after_the_device_is_unplugged() {
+    let device_name = "fpvm-test-unplugged";
+    let stub = Stub::new("umount-unplugged", None).await;
+    let shared_state = state(stub.config());
+    std::fs::create_dir_all(DEV_LOCATION).expect("device dir");
+    let device = DEV_LOCATION.to_owned() + device_name;
+    std::fs::write(&device, b"").expect("device");
+    record_mounted(&shared_state, device_name, &device);
+    stub.mount_layers(device_name);
+    std::fs::remove_file(&device).expect("unplugged device");
+
+    let response = umount_and_record(
+        device_name.to_owned(),
+        FnvHashMap::default(),
+        Arc::clone(&shared_state),
+    )
+    .await;
+
+    assert_eq!(
+        (response.status, response.code),
+        (200, "ok"),
+        "{}",
+        response.body
+    );
+    let (zip_mountpt, fuzzy_mountpt, content) = mountpoints(device_name);
+    let calls = stub.calls();
+    assert!(calls.contains(&format!("{} {}", UMOUNT, fuzzy_mountpt)));
+    assert!(calls.contains(&format!("{} {}", UMOUNT, zip_mountpt)));
+    assert_eq!(stub.mounted(), [UNIONFS_MOUNTPT]);
+    assert!(!Path::new(&device_dir(device_name)).exists());
+    let mount_status = shared_state.status.lock();
+    assert!(!mount_status.mounted.contains_key(&content));
+    assert!(!mount_status.changing.contains_key(&content));
+    assert!(mount_status.branches.is_empty());
+}
+
+#[tokio::test]
+async fn branch_check_evicts_a_dead_mount() {
+    let (stale_name, live_name) = ("fpvm-test-stale", "fpvm-test-live");
+    let stub = Stub::new("branch-check", None).await;
+    let shared_state = state(stub.config());
+    for device_name in [stale_name, live_name] {
+        record_mounted(
+            &shared_state,
+            device_name,
+            &(DEV_LOCATION.to_owned() + device_name),
+        );
+        stub.mount_layers(device_name);
+    }
+    shared_state.faults.mark_stale(stale_name);
+
+    check_branches(&shared_state).await;
+
+    let (stale_zip, stale_fuzzy, stale_content) = mountpoints(stale_name);
+    let (live_zip, live_fuzzy, live_content) = mountpoints(live_name);
+    {
+        let mount_status = shared_state.status.lock();
+        assert!(!mount_status.mounted.contains_key(&stale_content));
+        assert!(mount_status.mounted.contains_key(&live_content));
+        assert_eq!(mount_status.branches, [live_content.as_str()]);
+        assert!(mount_status.changing.is_empty());
+    }
+    let calls = stub.calls();
+    let union = calls
+        .iter()
+        .rev()
+        .find(|call| call.starts_with(UNIONFS))
+        .expect("union rebuilt");
+    assert_eq!(
+        union.split(' ').nth(1),
+        Some(format!("{}:{}", BASE_DIR, live_content).as_str())
+    );
+    assert!(calls.contains(&format!("{} -l {}", UMOUNT, stale_fuzzy)));
+    assert!(calls.contains(&format!("{} -l {}", UMOUNT, stale_zip)));
+    let mounted = stub.mounted();
+    assert!(!mounted.contains(&stale_zip) && !mounted.contains(&stale_fuzzy));
+    assert!(mounted.contains(&live_zip) && mounted.contains(&live_fuzzy));
+}
+
+#[tokio::test]
+async fn async_mount_is_turned_away_while_the_breaker_is_open() {
+    let device_name = "fpvm-test-breaker";
+    let mut config = Config::load(None).expect("default config");
+    config.breaker_threshold = Some(1);
+    let shared_state = state(config);
+    let config = shared_state.config();
+    assert!(shared_state
+        .breaker
+        .failed(1, config.breaker_window, config.breaker_cooldown));
+    let params: FnvHashMap<String, String> = [("async".to_owned(), "true".to_owned())]
+        .into_iter()
+        .collect();
+
+    let response = mount_request(device_name.to_owned(), params, Arc::clone(&shared_state)).await;
+
+    assert_eq!((response.status, response.code), (503, "circuit_open"));
+    let mount_status = shared_state.status.lock();
+    assert!(mount_status.changing.is_empty());
+    assert!(mount_status.mounted.is_empty());
+}
+
+#[tokio::test]
+async fn union_order_keeps_protected_branches_first() {
+    let (protected_name, other_name) = ("fpvm-test-protected", "fpvm-test-unprotected");
+    let mut config = Config::load(None).expect("default config");
+    config.protected_devices = vec![protected_name.to_owned()];
+    let shared_state = state(config);
+    for device_name in [protected_name, other_name] {
+        record_mounted(
+            &shared_state,
+            device_name,
+            &(DEV_LOCATION.to_owned() + device_name),
+        );
+    }
+    let (_, _, protected) = mountpoints(protected_name);
+    let (_, _, other) = mountpoints(other_name);
+
+    let response = set_union(vec![other, protected.clone()], Arc::clone(&shared_state)).await;
+
+    assert_eq!(
+        (response.status, response.code),
+        (400, "protected_branch_moved")
+    );
+    assert!(response.body.ends_with(&protected));
+}
+
+#[tokio::test]
+async fn union_order_has_to_keep_every_mounted_device() {
+    let (kept_name, left_out_name) = ("fpvm-test-kept", "fpvm-test-left-out");
+    let shared_state = state(Config::load(None).expect("default config"));
+    for device_name in [kept_name, left_out_name] {
+        record_mounted(
+            &shared_state,
+            device_name,
+            &(DEV_LOCATION.to_owned() + device_name),
+        );
+    }
+    let (_, _, kept) = mountpoints(kept_name);
+    let (_, _, left_out) = mountpoints(left_out_name);
+    let before = shared_state.status.lock().branches.clone();
+
+    let response = set_union(vec![kept], Arc::clone(&shared_state)).await;
+
+    assert_eq!((response.status, response.code), (400, "branch_left_out"));
+    assert!(response.body.ends_with(&left_out));
+    assert_eq!(shared_state.status.lock().branches, before);
+}
+
+#[test]
+fn list_carries_its_schema_version() {
+    let shared_state = state(Config::load(None).expect("default config"));
+    record_mounted(&shared_state, "fpvm-test-listed", "/dev/null");
+
+    let response = list_devices(&FnvHashMap::default(), &shared_state);
+
+    let body: Value = serde_json::from_str(&response.body).expect("JSON body");
+    assert_eq!(body["schema_version"], SCHEMA_VERSION);
+    assert_eq!(body["devices"], json!(["fpvm-test-listed"]));
+}
+
+/// Lets other tasks run, and the stub's commands finish, until `done` says so.
+async fn until(done: impl Fn() -> bool) {
+    while !done() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn quiet_period_umount_is_answered_before_the_rebuild() {
+    let device_name = "fpvm-test-quiet-umount";
+    let stub = Stub::new("quiet-umount", None).await;
+    let mut config = stub.config();
+    config.union_quiet_period = Some(Duration::from_millis(100));
+    let shared_state = state(config);
+    record_mounted(
+        &shared_state,
+        device_name,
+        &(DEV_LOCATION.to_owned() + device_name),
+    );
+    stub.mount_layers(device_name);
+    let (zip_mountpt, fuzzy_mountpt, content) = mountpoints(device_name);
+
+    let response = umount_and_record(
+        device_name.to_owned(),
+        FnvHashMap::default(),
+        Arc::clone(&shared_state),
+    )
+    .await;
+
+    assert_eq!((response.status, response.code), (200, "ok"));
+    assert!(!stub.calls().iter().any(|call| call.starts_with(UNIONFS)));
+    assert_eq!(
+        shared_state
+            .status
+            .lock()
+            .changing
+            .get(&content)
+            .map(|inflight| inflight.phase.name()),
+        Some("awaiting_rebuild")
+    );
+
+    until(|| !shared_state.status.lock().changing.contains_key(&content)).await;
+    let calls = stub.calls();
+    let union = calls
+        .iter()
+        .find(|call| call.starts_with(UNIONFS))
+        .expect("union rebuilt");
+    assert_eq!(union.split(' ').nth(1), Some(BASE_DIR));
+    assert!(calls.contains(&format!("{} {}", UMOUNT, fuzzy_mountpt)));
+    assert!(calls.contains(&format!("{} {}", UMOUNT, zip_mountpt)));
+    assert_eq!(stub.mounted(), [UNIONFS_MOUNTPT]);
+}
+
+#[tokio::test]
+async fn failed_quiet_period_rebuild_takes_the_mount_back_down() {
+    let device_name = "fpvm-test-quiet-mount";
+    let stub = Stub::new("quiet-mount", Some(UNIONFS)).await;
+    let mut config = stub.config();
+    config.union_quiet_period = Some(Duration::from_millis(10));
+    let shared_state = state(config);
+    record_mounted(
+        &shared_state,
+        device_name,
+        &(DEV_LOCATION.to_owned() + device_name),
+    );
+    stub.mount_layers(device_name);
+    let (_, _, content) = mountpoints(device_name);
+    if let Some(entry) = shared_state.status.lock().mounted.get_mut(&content) {
+        entry.pending_rebuild = true;
+    }
+
+    schedule_rebuild(
+        "mount",
+        device_name,
+        &content,
+        Duration::from_millis(10),
+        &shared_state,
+    );
+
+    until(|| {
+        shared_state
+            .status
+            .lock()
+            .failures
+            .contains_key(device_name)
+    })
+    .await;
+    {
+        let mount_status = shared_state.status.lock();
+        assert!(!mount_status.mounted.contains_key(&content));
+        assert!(mount_status.branches.is_empty());
+        assert!(mount_status.changing.is_empty());
+    }
+    let history = shared_state.history.to_json();
+    let last = history
+        .as_array()
+        .and_then(|records| records.last())
+        .expect("a record");
+    assert_eq!(last["operation"], "mount");
+    assert_eq!(last["devname"], device_name);
+    assert_eq!(last["success"], false);
+    assert_eq!(stub.mounted(), Vec::<String>::new());
+}
@@ -1,44 +1,462 @@
-use crate::{HTTPResponse, LockedMountStatus};
+use crate::{
+    config::Config, device_dir, error::MountError, retry::retry_with_backoff_if, server::Peer,
+    HTTPResponse, LockedMountStatus, UNIONFS_SHADOW_MOUNTPT,
+};
 use core::future::Future;
-use std::{collections::HashMap, hash::BuildHasher, sync::Arc};
+use log::error;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fs::{File, Metadata},
+    hash::BuildHasher,
+    io,
+    net::IpAddr,
+    os::unix::io::AsRawFd,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::fs::metadata;
+use tokio::task::{JoinError, JoinHandle};
 use urlencoding::decode;
-use warp::{http::Response, reject::Rejection};
+use warp::http::header::{HeaderMap, HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use warp::reply::with::WithHeaders;
+use warp::{http::Response, reject::Reject, reject::Rejection, Filter};
 
-/// Handle a request to an endpoint that needs a devname param.
+/// The version of the shape of our JSON responses. Every JSON response is an object, and carries it as
+/// `schema_version`, as well as in `X-Schema-Version`. It goes up by one whenever a field is removed, renamed or
+/// changes meaning; adding a field isn't a breaking change, so clients should ignore ones they don't know.
+///
+/// Version 1: answers to `/mount`, `/umount` and the like, errors included, are
+/// `{code, message, operation, timestamp}`.
+/// `/status`, `/probe` and `/verify` are objects with a `devname`; `/tree`, `/resolve`, `/consistency`, `/health`,
+/// `/version` and `/config` are objects; `/list`, `/orphans`, `/history` and `/debug/inflight` are arrays. `/list` holds
+/// devnames, or objects with a `devname`, `paths` and `ready` with `detail=true`.
+///
+/// Version 2: the arrays are wrapped in objects, so that they can carry `schema_version` too. `/list` is
+/// `{devices}`, `/orphans` is `{orphans}`, `/history` is `{history}` and `/debug/inflight` is `{operations}`.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Serializes a JSON response body, adding `schema_version` if it's an object, which it always should be.
+pub fn versioned(mut body: Value) -> String {
+    if let Value::Object(fields) = &mut body {
+        fields.insert("schema_version".to_owned(), SCHEMA_VERSION.into());
+    }
+    body.to_string()
+}
+
+/// Handle a request to an endpoint that needs a devname param. `operation` is what the endpoint does, like `mount`,
+/// for JSON answers to say. `known_params` are all the params the endpoint understands.
 pub async fn handle_devname<
-    T: BuildHasher,
+    T: BuildHasher + Send + Sync + 'static,
     U: BuildHasher,
-    F: Fn(String, Arc<LockedMountStatus<T>>) -> G,
-    G: Future<Output = HTTPResponse>,
+    F: Fn(String, HashMap<String, String, U>, Arc<LockedMountStatus<T>>) -> G,
+    G: Future<Output = HTTPResponse> + Send + 'static,
 >(
     shared_state: Arc<LockedMountStatus<T>>,
     map: HashMap<String, String, U>,
+    accept: Option<String>,
+    operation: &'static str,
+    known_params: &[&str],
     handle_param: F,
 ) -> Result<Response<String>, Rejection> {
-    let builder = Response::builder();
-    // Ensure that the "devname" param is set.
-    if let Some(name) = map.get("devname") {
-        if let Ok(decoded) = decode(name) {
-            // If it is, mount the device.
-            let mount_result = handle_param(decoded.into_owned(), shared_state).await;
-            //let mount_result = mount_device(&decoded, shared_state).await;
-            // Return the resulting status and body.
-            builder
-                .status(mount_result.status)
-                .body(mount_result.body)
-                // Any parsing Errors (there will be none) get turned into Rejections.
-                .map_err(|_| warp::reject())
+    let respond = responder(accept.as_deref(), operation);
+    if let Err(err) = check_params(&shared_state, &map, known_params) {
+        return respond(err.to_response());
+    }
+    // Ensure that the "devname" param is set, and usable.
+    let decoded = match check_devname(&shared_state, &map, "devname") {
+        Ok(decoded) => decoded,
+        Err(err) => return respond(err.to_response()),
+    };
+    // If it is, mount the device. The handler gets the rest of the params too.
+    // It runs as its own task, so that if it panics we can still answer the client.
+    let handled = tokio::spawn(handle_param(decoded.clone(), map, shared_state));
+    respond(join_handler(handled, &decoded).await)
+}
+
+/// Like `handle_devname`, but for an endpoint that needs two devnames, in the params `names`.
+pub async fn handle_devname_pair<
+    T: BuildHasher + Send + Sync + 'static,
+    U: BuildHasher,
+    F: Fn(String, String, HashMap<String, String, U>, Arc<LockedMountStatus<T>>) -> G,
+    G: Future<Output = HTTPResponse> + Send + 'static,
+>(
+    shared_state: Arc<LockedMountStatus<T>>,
+    map: HashMap<String, String, U>,
+    accept: Option<String>,
+    operation: &'static str,
+    names: (&str, &str),
+    known_params: &[&str],
+    handle_params: F,
+) -> Result<Response<String>, Rejection> {
+    let respond = responder(accept.as_deref(), operation);
+    if let Err(err) = check_params(&shared_state, &map, known_params) {
+        return respond(err.to_response());
+    }
+    let first = match check_devname(&shared_state, &map, names.0) {
+        Ok(decoded) => decoded,
+        Err(err) => return respond(err.to_response()),
+    };
+    let second = match check_devname(&shared_state, &map, names.1) {
+        Ok(decoded) => decoded,
+        Err(err) => return respond(err.to_response()),
+    };
+    let what = format!("{} and {}", first, second);
+    let handled = tokio::spawn(handle_params(first, second, map, shared_state));
+    respond(join_handler(handled, &what).await)
+}
+
+/// Picks how to answer a request, going by its `Accept` header. JSON answers say they're for `operation`.
+fn responder(
+    accept: Option<&str>,
+    operation: &'static str,
+) -> impl Fn(HTTPResponse) -> Result<Response<String>, Rejection> {
+    // Older clients parse the plaintext bodies, so JSON is strictly opt-in.
+    let json = accepts_json(accept);
+    move |response| {
+        if json {
+            to_json_response(response, operation)
         } else {
-            builder
-                .status(400)
-                .body("Couldn't decode devname".to_owned())
-                .map_err(|_| warp::reject())
+            to_response(response)
         }
-    } else {
-        // Whoops, no "devname" param. Yell at the user.
-        builder
-            .status(400)
-            .body("Required GET param absent: 'devname'".to_owned())
-            .map_err(|_| warp::reject())
     }
 }
+
+/// In strict mode, a typo'd param is an error, rather than something that silently does nothing.
+fn check_params<T: BuildHasher, U: BuildHasher>(
+    shared_state: &Arc<LockedMountStatus<T>>,
+    map: &HashMap<String, String, U>,
+    known_params: &[&str],
+) -> Result<(), MountError> {
+    if !shared_state.config().strict_params {
+        return Ok(());
+    }
+    let mut unknown: Vec<String> = map
+        .keys()
+        .filter(|key| !known_params.contains(&key.as_str()))
+        .cloned()
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    unknown.sort_unstable();
+    Err(MountError::UnknownParams(unknown))
+}
+
+/// Gets the devname out of the param `name`, and checks that it's one we can work with.
+fn check_devname<T: BuildHasher, U: BuildHasher>(
+    shared_state: &Arc<LockedMountStatus<T>>,
+    map: &HashMap<String, String, U>,
+    name: &str,
+) -> Result<String, MountError> {
+    // Whoops, no devname param. Yell at the user.
+    let raw = map.get(name).ok_or_else(|| MountError::InvalidDevname {
+        code: "devname_absent",
+        message: format!("Required GET param absent: '{}'", name),
+    })?;
+    let decoded = decode(raw).map_err(|_| MountError::InvalidDevname {
+        code: "devname_undecodable",
+        message: "Couldn't decode ".to_owned() + name,
+    })?;
+    // A blank name would turn into DEV_LOCATION itself, which is never what anyone meant.
+    if decoded.trim().is_empty() {
+        return Err(MountError::InvalidDevname {
+            code: "empty_devname",
+            message: "Devname is empty.".to_owned(),
+        });
+    }
+    // Overly long names would only produce paths that the mount tools choke on.
+    let max_len = shared_state.config().max_devname_len;
+    if decoded.len() > max_len {
+        return Err(MountError::InvalidDevname {
+            code: "devname_too_long",
+            message: format!("Devname is longer than {} bytes.", max_len),
+        });
+    }
+    // The shadow union lives in the tmp dir too, so no device may take its name.
+    if device_dir(&decoded) == UNIONFS_SHADOW_MOUNTPT {
+        return Err(MountError::InvalidDevname {
+            code: "devname_collides",
+            message: "Devname is reserved: ".to_owned() + &decoded,
+        });
+    }
+    Ok(decoded.into_owned())
+}
+
+/// Waits for a handler's task, turning a panic into a 500. `what` is whatever it was working on, for the log.
+async fn join_handler(handled: JoinHandle<HTTPResponse>, what: &str) -> HTTPResponse {
+    match handled.await {
+        Ok(response) => response,
+        Err(err) => {
+            error!("Handler for {} failed: {}", what, panic_message(err));
+            MountError::Internal.to_response()
+        }
+    }
+}
+
+/// Gets the panic message out of a failed task, if there is one.
+fn panic_message(err: JoinError) -> String {
+    match err.try_into_panic() {
+        Ok(payload) => {
+            if let Some(msg) = payload.downcast_ref::<&str>() {
+                (*msg).to_owned()
+            } else if let Some(msg) = payload.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                "panicked".to_owned()
+            }
+        }
+        Err(err) => err.to_string(),
+    }
+}
+
+/// Checks the `Authorization` header of an admin request. Returns an error, or `None` if the request may proceed.
+pub fn check_auth<T: BuildHasher>(
+    shared_state: &Arc<LockedMountStatus<T>>,
+    header: Option<String>,
+) -> Option<MountError> {
+    // No token configured means nobody gets in.
+    let config = shared_state.config();
+    let token = match &config.admin_token {
+        Some(token) => token,
+        None => return Some(MountError::AdminDisabled),
+    };
+    match header
+        .as_deref()
+        .and_then(|val| val.strip_prefix("Bearer "))
+    {
+        Some(given) if given == token => None,
+        _ => Some(MountError::Unauthorized),
+    }
+}
+
+/// Reads a boolean query param, which has to be `true`/`1` or `false`/`0`. Falls back to `default` if it's absent.
+pub fn parse_bool_param<U: BuildHasher>(
+    params: &HashMap<String, String, U>,
+    name: &str,
+    default: bool,
+) -> Result<bool, MountError> {
+    match params.get(name).map(String::as_str) {
+        None => Ok(default),
+        Some("true") | Some("1") => Ok(true),
+        Some("false") | Some("0") => Ok(false),
+        Some(_) => Err(MountError::InvalidParam(format!("Couldn't parse {}", name))),
+    }
+}
+
+/// Opens a device node and takes an exclusive, non-blocking flock on it. Fails with `WouldBlock` if
+/// another process holds the lock. The lock is released when the returned file is dropped.
+pub fn lock_device(devpath: &str) -> io::Result<File> {
+    let file = File::open(devpath)?;
+    // SAFETY: the fd belongs to `file`, which outlives this call.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+/// Stats a device, like `metadata`. Under heavy load a stat can fail for reasons that have nothing to do with whether
+/// the device is there, so those are retried as `config` says before giving up.
+pub async fn stat_device(devpath: &str, config: &Config) -> io::Result<Metadata> {
+    retry_with_backoff_if(
+        config.retry_attempts,
+        config.retry_base_delay,
+        is_transient,
+        |_| metadata(devpath),
+    )
+    .await
+}
+
+/// Whether a failed stat means there's nothing at the path, as opposed to us failing to look.
+pub fn is_not_found(err: &io::Error) -> bool {
+    // ENOTDIR: some part of the path is a file, so there's nothing below it either.
+    err.kind() == io::ErrorKind::NotFound || err.raw_os_error() == Some(libc::ENOTDIR)
+}
+
+/// Whether an IO error is worth trying again straight away.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    ) || matches!(
+        err.raw_os_error(),
+        Some(libc::ENOMEM) | Some(libc::ENFILE) | Some(libc::EMFILE)
+    )
+}
+
+/// Turns an `HTTPResponse` into something warp can send.
+pub fn to_response(response: HTTPResponse) -> Result<Response<String>, Rejection> {
+    // Nothing we answer here should be cached: a stale 409 is worse than no answer at all.
+    let mut builder = Response::builder()
+        .status(response.status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(CACHE_CONTROL, "no-store");
+    // Failures carry a machine-readable code, so that clients don't have to parse the body.
+    if response.status >= 400 {
+        builder = builder.header("X-Error-Code", response.code);
+    }
+    builder
+        .body(response.body)
+        // Any parsing Errors (there will be none) get turned into Rejections.
+        .map_err(|_| warp::reject())
+}
+
+/// Like `to_response`, but wraps the code and message in a JSON object, with what it's an answer to and the time.
+/// The status is the same either way.
+fn to_json_response(
+    response: HTTPResponse,
+    operation: &'static str,
+) -> Result<Response<String>, Rejection> {
+    let status = response.status;
+    let code = response.code;
+    // When it was answered, for matching it up with our log.
+    let body = versioned(json!({
+        "code": code,
+        "message": response.body,
+        "operation": operation,
+        "timestamp": format_timestamp(SystemTime::now()),
+    }));
+    let mut response = to_response(HTTPResponse { status, code, body })?;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert("X-Schema-Version", HeaderValue::from(SCHEMA_VERSION));
+    Ok(response)
+}
+
+/// Checks whether an `Accept` header lists `application/json`, ignoring parameters other than a zero `q`.
+fn accepts_json(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        accept.split(',').any(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            parts.next() == Some("application/json")
+                && !parts.any(|param| matches!(param, "q=0" | "q=0.0" | "q=0.00" | "q=0.000"))
+        })
+    })
+}
+
+/// Formats a point in time as an ISO-8601 UTC timestamp, like `2022-07-04T12:34:56Z`.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|dur| dur.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Convert days since the epoch to a civil date. See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Headers for routes that answer with JSON. Like everything else we send, it reflects live state, so it mustn't be cached.
+/// It also says which `SCHEMA_VERSION` the body follows.
+pub fn json_headers() -> WithHeaders {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert("X-Schema-Version", HeaderValue::from(SCHEMA_VERSION));
+    warp::reply::with::headers(headers)
+}
+
+/// Rejection for requests whose query string is longer than allowed.
+#[derive(Debug)]
+pub struct QueryTooLong;
+
+impl Reject for QueryTooLong {}
+
+/// A filter that rejects any request whose raw query string is longer than `max_len` bytes.
+pub fn query_length_limit(max_len: usize) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    // A request without a query string has nothing to check.
+    warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify()
+        .and_then(move |query: String| async move {
+            if query.len() > max_len {
+                Err(warp::reject::custom(QueryTooLong))
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one()
+}
+
+/// Rejection for requests from clients that aren't in the allowed client list.
+#[derive(Debug)]
+pub struct ClientNotAllowed;
+
+impl Reject for ClientNotAllowed {}
+
+/// A filter that rejects any request from a client that isn't allowed to use the daemon. Requests from trusted
+/// proxies are judged by the client they forwarded for.
+pub fn client_allowlist<T: BuildHasher + Send + Sync + 'static>(
+    shared_state: Arc<LockedMountStatus<T>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::ext::optional::<Peer>()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and_then(move |peer: Option<Peer>, forwarded: Option<String>| {
+            let config = shared_state.config();
+            async move {
+                if config.allowed_clients.is_empty() {
+                    return Ok(());
+                }
+                match client_address(
+                    &config,
+                    peer.map(|Peer(peer)| peer.ip()),
+                    forwarded.as_deref(),
+                ) {
+                    Some(client) if config.allowed_clients.contains(&client) => Ok(()),
+                    _ => Err(warp::reject::custom(ClientNotAllowed)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Works out who a request is really from. That's the peer, unless the peer is a trusted proxy, in which case it's
+/// the last address in `X-Forwarded-For` that isn't one of our proxies: anything to the left of that was added by
+/// somebody we don't trust, and could be made up. `None` if we can't tell, such as when a proxy sends us a mangled
+/// header, or none at all.
+fn client_address(
+    config: &Config,
+    peer: Option<IpAddr>,
+    forwarded: Option<&str>,
+) -> Option<IpAddr> {
+    let peer = peer?;
+    if !config.trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+    let mut client = None;
+    for hop in forwarded?.rsplit(',') {
+        let hop: IpAddr = hop.trim().parse().ok()?;
+        client = Some(hop);
+        if !config.trusted_proxies.contains(&hop) {
+            break;
+        }
+    }
+    client
+}
+
+/// Turns our own rejections into responses. Anything else is left for warp to deal with.
+pub async fn handle_rejection(err: Rejection) -> Result<Response<String>, Rejection> {
+    if err.find::<QueryTooLong>().is_some() {
+        return to_response(MountError::QueryTooLong.to_response());
+    }
+    if err.find::<ClientNotAllowed>().is_some() {
+        return to_response(MountError::ClientNotAllowed.to_response());
+    }
+    Err(err)
+}
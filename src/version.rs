@@ -0,0 +1,90 @@
+use log::{info, warn};
+use std::{process::Stdio, time::Duration};
+use tokio::{process::Command, time::timeout};
+
+/// The oldest unionfs-fuse we know handles the branch syntax and options we pass it.
+const MIN_UNIONFS_VERSION: (u32, u32) = (1, 0);
+/// `--version` and `--help` shouldn't take any time at all. Anything longer means the binary is wedged.
+const VERSION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Asks the unionfs binary at `program` for its version, and logs it. Warns if it's older than we support.
+/// Returns the version line it printed, or `None` if it couldn't be run or didn't say.
+pub async fn check_unionfs(program: &str) -> Option<String> {
+    // unionfs --version
+    let text = run_for_text(program, "--version").await?;
+    let line = match text
+        .lines()
+        .map(str::trim)
+        .find(|line| parse_version(line).is_some())
+    {
+        Some(line) => line.to_owned(),
+        None => {
+            warn!("{} --version didn't print a version", program);
+            return None;
+        }
+    };
+    info!("Using {}", line);
+    if let Some(version) = parse_version(&line) {
+        if version < MIN_UNIONFS_VERSION {
+            warn!(
+                "unionfs {}.{} is older than {}.{}, the oldest version known to work. Expect mounts to fail.",
+                version.0, version.1, MIN_UNIONFS_VERSION.0, MIN_UNIONFS_VERSION.1
+            );
+        }
+    }
+    Some(line)
+}
+
+/// Asks the unionfs binary at `program` which `-o` options it understands, going by its `--help`. Options that take
+/// a value end in `=`, like `chroot=`. Returns `None` if it couldn't be run or didn't list any.
+pub async fn unionfs_options(program: &str) -> Option<Vec<String>> {
+    // unionfs --help
+    let text = run_for_text(program, "--help").await?;
+    let options: Vec<String> = text
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("-o "))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .map(|option| match option.find('=') {
+            Some(eq) => option[..=eq].to_owned(),
+            None => option.to_owned(),
+        })
+        .collect();
+    if options.is_empty() {
+        warn!("{} --help didn't list any options", program);
+        return None;
+    }
+    Some(options)
+}
+
+/// Runs `program` with a single informational `arg`, and returns everything it printed.
+async fn run_for_text(program: &str, arg: &str) -> Option<String> {
+    // Run it directly: printing its version or help doesn't need privileges.
+    let output = Command::new(program)
+        .arg(arg)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let output = match timeout(VERSION_TIMEOUT, output).await {
+        Ok(Ok(output)) => output,
+        _ => {
+            warn!("Could not run {} {}", program, arg);
+            return None;
+        }
+    };
+    // Depending on the version, it goes to either stream.
+    Some(
+        String::from_utf8_lossy(&output.stdout).into_owned()
+            + &String::from_utf8_lossy(&output.stderr),
+    )
+}
+
+/// Finds the first `major.minor` version number in `text`, like the `2.1` in `unionfs-fuse version: 2.1`.
+fn parse_version(text: &str) -> Option<(u32, u32)> {
+    text.split(|c: char| !c.is_ascii_digit() && c != '.')
+        .find_map(|word| {
+            let mut parts = word.split('.');
+            let major = parts.next()?.parse().ok()?;
+            let minor = parts.next()?.parse().ok()?;
+            Some((major, minor))
+        })
+}
@@ -0,0 +1,96 @@
+use log::{debug, info};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::fs::{read_dir, File};
+use tokio::io::AsyncReadExt;
+
+/// How much of a file to read at a time while warming.
+const WARM_CHUNK: usize = 64 * 1024;
+
+/// How far the cache warming of one mounted device has got.
+#[derive(Default)]
+pub struct WarmProgress {
+    files: AtomicU64,
+    bytes: AtomicU64,
+    done: AtomicBool,
+    cancelled: AtomicBool,
+}
+
+impl WarmProgress {
+    /// Asks the warming task to stop. It checks between reads, so it stops holding files open soon after.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// The progress so far, for `/status`.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "files": self.files.load(Ordering::Relaxed),
+            "bytes": self.bytes.load(Ordering::Relaxed),
+            "done": self.done.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Whether every file has been read, or warming was stopped early.
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Reads every file under `branches` once and throws the data away, so that fuse-archive has
+/// already decompressed it by the time anyone asks for it. Symlinks aren't followed.
+/// Unreadable files and folders are skipped.
+pub async fn warm(label: String, branches: Vec<String>, progress: Arc<WarmProgress>) {
+    let mut pending: Vec<PathBuf> = branches.into_iter().map(PathBuf::from).collect();
+    let mut buf = vec![0u8; WARM_CHUNK];
+    while let Some(dir) = pending.pop() {
+        let mut entries = match read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                debug!("Not warming {}: {}", dir.display(), err);
+                continue;
+            }
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if progress.cancelled() {
+                info!("Stopped warming {}", label);
+                return;
+            }
+            let file_type = match entry.file_type().await {
+                Ok(file_type) => file_type,
+                Err(_) => continue,
+            };
+            if file_type.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+            if !file_type.is_file() {
+                continue;
+            }
+            let mut file = match File::open(entry.path()).await {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            while let Ok(read) = file.read(&mut buf).await {
+                if read == 0 || progress.cancelled() {
+                    break;
+                }
+                progress.bytes.fetch_add(read as u64, Ordering::Relaxed);
+            }
+            progress.files.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    progress.done.store(true, Ordering::Relaxed);
+    info!(
+        "Finished warming {}: {} files, {} bytes",
+        label,
+        progress.files.load(Ordering::Relaxed),
+        progress.bytes.load(Ordering::Relaxed)
+    );
+}
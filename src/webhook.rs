@@ -0,0 +1,60 @@
+use crate::retry::retry_with_backoff;
+use log::warn;
+use serde_json::Value;
+use std::time::Duration;
+use warp::hyper::{header::CONTENT_TYPE, Body, Client, Method, Request, Uri};
+
+/// POSTs `event` to the webhook at `url` as JSON, in the background. Delivery is tried up to `attempts` times, backing
+/// off from `base_delay`, but never holds up the caller: failures only get logged. Only plain `http://` URLs are
+/// supported.
+pub fn notify(url: &str, event: Value, attempts: u32, base_delay: Duration) {
+    let uri: Uri = match url.parse() {
+        Ok(uri) => uri,
+        Err(err) => {
+            warn!("Invalid webhook URL {}: {}", url, err);
+            return;
+        }
+    };
+    let body = event.to_string();
+    tokio::spawn(async move {
+        let client = Client::new();
+        let _ = retry_with_backoff(attempts, base_delay, |attempt| {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(uri.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.clone()));
+            let client = &client;
+            async move {
+                let request = match request {
+                    Ok(request) => request,
+                    Err(err) => {
+                        // Building it again won't help.
+                        warn!("Could not build webhook request: {}", err);
+                        return Ok(());
+                    }
+                };
+                match client.request(request).await {
+                    Ok(response) if response.status().is_success() => Ok(()),
+                    Ok(response) => {
+                        warn!(
+                            "Webhook answered {} (attempt {}/{})",
+                            response.status(),
+                            attempt,
+                            attempts
+                        );
+                        Err(())
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Webhook delivery failed (attempt {}/{}): {}",
+                            attempt, attempts, err
+                        );
+                        Err(())
+                    }
+                }
+            }
+        })
+        .await;
+    });
+}